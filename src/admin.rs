@@ -0,0 +1,92 @@
+use super::{
+    backend::PaymentBackend,
+    config::Config,
+    payout::{ManualRun, Scheduler},
+    watcher,
+};
+
+use actix_web::{
+    error, post,
+    web::{self, Json},
+    Error as ActixError, HttpResponse, Result,
+};
+use sled::Db;
+
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct SweepPreviewRq {
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct SweepExecuteRq {
+    password: String,
+
+    /// The confirmation code of the preview being approved
+    confirmation: String,
+}
+
+/// Rejects requests that don't carry the admin password.
+pub fn check_admin_pass(cfg: &Config, password: &str) -> Result<()> {
+    if !watcher::secrets_match(password.as_bytes(), cfg.admin_pass.as_bytes()) {
+        return Err(error::ErrorUnauthorized("no password provided"));
+    }
+
+    Ok(())
+}
+
+/// Shows the sweep that would happen right now, without broadcasting anything.
+#[post("/admin/sweep/preview")]
+pub async fn sweep_preview(
+    db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
+    btcapi: web::Data<Arc<dyn PaymentBackend>>,
+    json_info: Json<SweepPreviewRq>,
+) -> Result<HttpResponse, ActixError> {
+    check_admin_pass(&cfg, &json_info.password)?;
+
+    Scheduler::new((**db_arc).clone(), btcapi.get_ref().as_ref(), &cfg)
+        .preview()
+        .await
+        .map(|preview| HttpResponse::Ok().json(preview))
+        .map_err(|e| error::ErrorInternalServerError(e))
+}
+
+/// Lists every payout run recorded, oldest first.
+#[post("/admin/sweep/history")]
+pub async fn sweep_history(
+    db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
+    btcapi: web::Data<Arc<dyn PaymentBackend>>,
+    json_info: Json<SweepPreviewRq>,
+) -> Result<HttpResponse, ActixError> {
+    check_admin_pass(&cfg, &json_info.password)?;
+
+    Scheduler::new((**db_arc).clone(), btcapi.get_ref().as_ref(), &cfg)
+        .runs()
+        .map(|runs| HttpResponse::Ok().json(runs))
+        .map_err(|e| error::ErrorInternalServerError(e))
+}
+
+/// Executes a previewed sweep. If the sweep has changed since it was previewed, nothing is
+/// broadcast and the new preview is returned for confirmation instead.
+#[post("/admin/sweep/execute")]
+pub async fn sweep_execute(
+    db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
+    btcapi: web::Data<Arc<dyn PaymentBackend>>,
+    json_info: Json<SweepExecuteRq>,
+) -> Result<HttpResponse, ActixError> {
+    check_admin_pass(&cfg, &json_info.password)?;
+
+    match Scheduler::new((**db_arc).clone(), btcapi.get_ref().as_ref(), &cfg)
+        .execute(&json_info.confirmation)
+        .await
+        .map_err(|e| error::ErrorInternalServerError(e))?
+    {
+        ManualRun::Ran(run) => Ok(HttpResponse::Ok().json(run)),
+        ManualRun::Changed(preview) => Ok(HttpResponse::Conflict().json(preview)),
+        ManualRun::Busy => Err(error::ErrorConflict("a sweep is already running")),
+    }
+}
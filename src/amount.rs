@@ -0,0 +1,279 @@
+use serde::{de, Deserializer};
+
+use std::{
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign},
+    str::FromStr,
+};
+
+/// The number of satoshis in one bitcoin.
+pub const SAT_PER_BTC: u64 = 100_000_000;
+
+/// The number of decimal places in a bitcoin amount.
+pub const BTC_DECIMALS: usize = 8;
+
+/// The number of decimal places in a monero amount, whose smallest unit is the piconero.
+pub const XMR_DECIMALS: usize = 12;
+
+/// An exact amount of bitcoin, in satoshis. Stored as a plain integer, and converted to and from
+/// decimal BTC strings at the edges (bitcoind, config, templates). With the monero backend it
+/// counts piconero instead, and is shown to 12 decimal places.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_sat(sat: u64) -> Self {
+        Self(sat)
+    }
+
+    pub const fn as_sat(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+
+    /// Parses a decimal BTC amount such as "0.0002" exactly, rejecting anything finer than a
+    /// satoshi.
+    pub fn from_btc_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid BTC amount {:?}", s);
+        let (whole, frac) = match s.find('.') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+
+        if (whole.is_empty() && frac.is_empty())
+            || frac.len() > BTC_DECIMALS
+            || !whole
+                .chars()
+                .chain(frac.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        let whole: u64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| invalid())?
+        };
+        let frac: u64 = format!("{:0<width$}", frac, width = BTC_DECIMALS)
+            .parse()
+            .map_err(|_| invalid())?;
+
+        whole
+            .checked_mul(SAT_PER_BTC)
+            .and_then(|sat| sat.checked_add(frac))
+            .map(Self)
+            .ok_or_else(invalid)
+    }
+
+    /// Converts an amount written to 8 decimal places, as amounts in the config are, into units
+    /// with the given number of decimal places. Saturates rather than overflowing.
+    pub fn scaled_to(self, decimals: usize) -> Self {
+        let scale = 10u64.pow(decimals.saturating_sub(BTC_DECIMALS) as u32);
+
+        Self(self.0.saturating_mul(scale))
+    }
+
+    /// Formats the amount as a decimal with the given number of decimal places, the smallest
+    /// unit of its currency.
+    pub fn to_decimal(self, decimals: usize) -> String {
+        let unit = 10u64.pow(decimals as u32);
+
+        format!(
+            "{}.{:0width$}",
+            self.0 / unit,
+            self.0 % unit,
+            width = decimals
+        )
+    }
+
+    /// Converts a JSON number decoded by serde_json into an exact amount. Any amount with at
+    /// most 8 decimals and no more than 21M BTC round-trips exactly through its shortest f64
+    /// representation, so re-parsing that representation recovers the value bitcoind sent.
+    pub fn from_btc_f64(btc: f64) -> Result<Self, String> {
+        if !btc.is_finite() || btc < 0.0 {
+            return Err(format!("invalid BTC amount {}", btc));
+        }
+
+        Self::from_btc_str(&format!("{}", btc))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_decimal(BTC_DECIMALS))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_btc_str(s)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Self) -> Self {
+        self.checked_add(rhs).expect("bitcoin amount overflowed")
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Amount::ZERO, Add::add)
+    }
+}
+
+/// Deserializes an Amount from decimal BTC, for bitcoind's JSON and the config file. Numbers and
+/// strings are both accepted.
+pub mod as_btc {
+    use super::*;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        deserializer.deserialize_any(BtcVisitor)
+    }
+
+    struct BtcVisitor;
+
+    impl<'de> de::Visitor<'de> for BtcVisitor {
+        type Value = Amount;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a decimal amount of BTC")
+        }
+
+        fn visit_u64<E: de::Error>(self, btc: u64) -> Result<Amount, E> {
+            btc.checked_mul(SAT_PER_BTC)
+                .map(Amount)
+                .ok_or_else(|| E::custom(format!("invalid BTC amount {}", btc)))
+        }
+
+        fn visit_i64<E: de::Error>(self, btc: i64) -> Result<Amount, E> {
+            if btc < 0 {
+                return Err(E::custom(format!("invalid BTC amount {}", btc)));
+            }
+
+            self.visit_u64(btc as u64)
+        }
+
+        fn visit_f64<E: de::Error>(self, btc: f64) -> Result<Amount, E> {
+            Amount::from_btc_f64(btc).map_err(E::custom)
+        }
+
+        fn visit_str<E: de::Error>(self, btc: &str) -> Result<Amount, E> {
+            Amount::from_btc_str(btc).map_err(E::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_btc_str() {
+        assert_eq!(
+            Amount::from_btc_str("0.0002").unwrap(),
+            Amount::from_sat(20_000)
+        );
+        assert_eq!(
+            Amount::from_btc_str("1").unwrap(),
+            Amount::from_sat(SAT_PER_BTC)
+        );
+        assert_eq!(
+            Amount::from_btc_str(".5").unwrap(),
+            Amount::from_sat(50_000_000)
+        );
+        assert_eq!(
+            Amount::from_btc_str("20999999.99999999").unwrap(),
+            Amount::from_sat(2_099_999_999_999_999)
+        );
+
+        assert!(Amount::from_btc_str("0.000000001").is_err());
+        assert!(Amount::from_btc_str("-1").is_err());
+        assert!(Amount::from_btc_str("1e-4").is_err());
+        assert!(Amount::from_btc_str(".").is_err());
+        assert!(Amount::from_btc_str("").is_err());
+    }
+
+    #[test]
+    fn test_from_btc_f64() {
+        // The float sum of these is famously not 0.0003
+        let sum = Amount::from_btc_f64(0.0001).unwrap() + Amount::from_btc_f64(0.0002).unwrap();
+        assert_eq!(sum, Amount::from_btc_str("0.0003").unwrap());
+
+        assert_eq!(
+            Amount::from_btc_f64(0.00016927).unwrap(),
+            Amount::from_sat(16_927)
+        );
+        assert!(Amount::from_btc_f64(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Amount::from_sat(20_000).to_string(), "0.00020000");
+        assert_eq!(Amount::from_sat(SAT_PER_BTC + 1).to_string(), "1.00000001");
+        assert_eq!(Amount::ZERO.to_string(), "0.00000000");
+
+        // Piconero
+        assert_eq!(
+            Amount::from_sat(50_000_000_001).to_decimal(XMR_DECIMALS),
+            "0.050000000001"
+        );
+    }
+
+    #[test]
+    fn test_scaled_to() {
+        let price = Amount::from_btc_str("0.05").unwrap();
+
+        assert_eq!(price.scaled_to(BTC_DECIMALS), price);
+        assert_eq!(
+            price.scaled_to(XMR_DECIMALS),
+            Amount::from_sat(50_000_000_000)
+        );
+        assert_eq!(
+            Amount::from_sat(u64::MAX).scaled_to(XMR_DECIMALS),
+            Amount::from_sat(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_as_btc() {
+        #[derive(Deserialize)]
+        struct Utxo {
+            #[serde(with = "as_btc")]
+            amount: Amount,
+        }
+
+        let utxo: Utxo = serde_json::from_str(r#"{"amount": 0.00016927}"#).unwrap();
+        assert_eq!(utxo.amount, Amount::from_sat(16_927));
+
+        let utxo: Utxo = serde_json::from_str(r#"{"amount": "2"}"#).unwrap();
+        assert_eq!(utxo.amount, Amount::from_sat(2 * SAT_PER_BTC));
+    }
+}
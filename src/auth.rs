@@ -1,29 +1,30 @@
 use super::{
-    ingress::RpcConnection,
-    user::{User, MONTHLY_BTC},
+    backend::PaymentBackend,
+    config::Config,
+    ledger::{self, Subscription},
+    lightning::{Invoice, Lnd},
+    links::{self, FollowError},
+    qr,
+    session::{Authenticated, Session, SESSION_COOKIE},
+    signed_links::{self, LinkMode, VerifyError},
+    store::{self, Record},
+    user::User,
 };
 
 use actix_files as afs;
 use actix_web::{
-    cookie::Cookie,
     error, get, http,
     http::StatusCode,
     post,
     web::{self, Json, Path},
-    Error as ActixError, HttpMessage, HttpRequest, HttpResponse, Responder, Result,
+    Error as ActixError, FromRequest, HttpRequest, HttpResponse, Responder, Result,
 };
 use askama::Template;
+use chrono::{DateTime, Utc};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use sled::Db;
 
-use std::{
-    fs,
-    time::{Duration, SystemTime},
-};
-
-const ITEMS_PER_PAGE: usize = 5;
-const ITEM_PATH: &'static str = env!("CONTENT_FOLDER");
-const ADMIN_PASS: &'static str = env!("ADMIN_PASS");
+use std::{fs, path::PathBuf, sync::Arc, time::SystemTime};
 
 #[derive(Deserialize, Debug)]
 pub struct Registration {
@@ -31,13 +32,63 @@ pub struct Registration {
     password: String,
 }
 
+/// A form posted from one of the store's own pages by a logged in user.
+#[derive(Deserialize)]
+pub struct CsrfForm {
+    csrf_token: String,
+}
+
 #[derive(Template)]
 #[template(path = "account_overview.html")]
 struct OverviewTemplate<'a> {
     username: &'a str,
-    balance: f64,
-    account_wallets: Vec<String>,
-    balance_insufficient: bool,
+    credit: String,
+    monthly_price: String,
+    currency: &'static str,
+    currency_name: &'static str,
+    paid_until: String,
+
+    /// The user's addresses that haven't received anything yet, to deposit to
+    account_wallets: Vec<&'a str>,
+    subscription_inactive: bool,
+    csrf_token: String,
+
+    /// Deposits that can't be credited until they have min_confirmations
+    pending_deposits: Vec<PendingView>,
+    min_confirmations: u32,
+
+    /// Whether or not Lightning invoices are offered, and the one waiting to be paid, if any
+    lightning: bool,
+    invoice: Option<InvoiceView>,
+}
+
+/// A deposit waiting for confirmations, as shown on the account overview.
+struct PendingView {
+    amount: String,
+    confirmations: u64,
+}
+
+/// A Lightning invoice as shown on the account overview.
+struct InvoiceView {
+    payment_request: String,
+
+    /// The invoice's QR code, as a data: URI
+    qr: String,
+    expires_at: String,
+}
+
+impl InvoiceView {
+    fn new(invoice: Invoice) -> Result<Self> {
+        Ok(Self {
+            // Wallets accept uppercase invoices, which make for a smaller QR code
+            qr: qr::png_data_uri(&format!("lightning:{}", invoice.payment_request).to_uppercase())
+                .map_err(|e| error::ErrorInternalServerError(e))?,
+            expires_at: DateTime::<Utc>::from(invoice.expires_at)
+                .format("%H:%M UTC")
+                .to_string(),
+            payment_request: invoice.payment_request,
+        })
+    }
 }
 
 #[derive(Template)]
@@ -49,8 +100,40 @@ struct FeedTemplate {
 
 #[derive(Serialize, Deserialize)]
 pub struct PostHist {
-    path: String,
-    created_at: SystemTime,
+    pub path: String,
+    pub created_at: SystemTime,
+
+    /// The token of the session the link was issued to, the only one that can follow it
+    pub session: String,
+
+    /// Whether the content has been sent in full, after which only ranges of it are served
+    pub delivered: bool,
+}
+
+/// A link as it was stored before links were bound to a session.
+#[derive(Serialize, Deserialize)]
+pub struct PostHistV1 {
+    pub path: String,
+    pub created_at: SystemTime,
+}
+
+impl Record for PostHist {
+    const VERSION: u32 = 2;
+
+    fn upgrade(version: u32, body: &[u8]) -> Result<Self, String> {
+        match version {
+            // No session can follow a link that wasn't issued to one
+            1 => bincode::deserialize(body)
+                .map(|hist: PostHistV1| PostHist {
+                    path: hist.path,
+                    created_at: hist.created_at,
+                    session: String::new(),
+                    delivered: false,
+                })
+                .map_err(|e| e.to_string()),
+            _ => Err(format!("no upgrade from version {}", version)),
+        }
+    }
 }
 
 pub struct Post {
@@ -83,64 +166,35 @@ macro_rules! auth_user {
 #[get("/feed/{i}.html")]
 pub async fn load_feed_page(
     db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
     info: Path<usize>,
-    req: HttpRequest,
+    sess: Authenticated,
 ) -> Result<HttpResponse, ActixError> {
     let mut db = (**db_arc).clone();
 
-    let username = req
-        .cookie("username")
-        .ok_or(error::ErrorBadRequest("no username".to_owned()))?
-        .to_string();
-    let username_bytes: Vec<u8> =
-        bincode::serialize::<str>(username.split("=").skip(1).collect::<Vec<&str>>()[0])
-            .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
-
-    // Get the entry for the user indicated by the username cookie
-    let u: User = db
-        .get(username_bytes)
-        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
-        .ok_or(error::ErrorInternalServerError(
-            "user doesn't exist".to_owned(),
-        ))
-        .and_then(|u_bytes| {
-            bincode::deserialize(u_bytes.to_vec().as_slice())
-                .map_err(|e| error::ErrorInternalServerError(e.to_string()))
-        })?;
-
-    auth_user!(
-        u,
-        req.cookie("password")
-            .ok_or(error::ErrorInternalServerError("no password"))?
-            .to_string()
-            .split("=")
-            .skip(1)
-            .collect::<Vec<&str>>()[0]
-    );
-
-    load_feed(&mut db, info.0).await.map(|resp| {
-        HttpResponse::build(StatusCode::OK)
-            .content_type("text/html; charset=utf-8")
-            .body(resp)
-    })
+    load_feed(&mut db, &cfg, *info, &sess.token, &sess.user.username)
+        .await
+        .map(|resp| {
+            HttpResponse::build(StatusCode::OK)
+                .content_type("text/html; charset=utf-8")
+                .body(resp)
+        })
 }
 
 /// Registers a new post
 #[post("/new_post")]
 pub async fn new_post(
     db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
     json_info: Json<PostRq>,
 ) -> Result<HttpResponse, ActixError> {
-    if json_info.password != ADMIN_PASS {
+    if json_info.password != cfg.admin_pass {
         return Err(error::ErrorUnauthorized("no password provided"));
     }
 
-    let db = (**db_arc).clone();
-    db.insert(
-        bincode::serialize(&json_info.src).map_err(|e| error::ErrorInternalServerError(e))?,
-        bincode::serialize(&json_info.caption).map_err(|e| error::ErrorInternalServerError(e))?,
-    )
-    .map_err(|e| error::ErrorInternalServerError(e))?;
+    store::posts(&db_arc)
+        .and_then(|posts| posts.insert(&json_info.src, &json_info.caption))
+        .map_err(|e| error::ErrorInternalServerError(e))?;
 
     Ok(HttpResponse::Ok().content_type("plain/text").body("yay"))
 }
@@ -149,49 +203,50 @@ pub async fn new_post(
 #[post("/login")]
 pub async fn login(
     db_arc: web::Data<Db>,
-    btcapi: web::Data<RpcConnection<'_>>,
+    cfg: web::Data<Config>,
+    lnd: web::Data<Option<Lnd>>,
     form: web::Form<Registration>,
 ) -> impl Responder {
     let form_data = form.into_inner();
     let mut db = (**db_arc).clone();
 
     // Load the user from the database so we can verify that their password is correct
-    let u: User = db
-        .get(
-            bincode::serialize(&form_data.username)
-                .map_err(|e| error::ErrorInternalServerError(e))?,
-        )
+    let u = User::load(&db, &form_data.username)
         .map_err(|e| error::ErrorInternalServerError(e))?
-        .ok_or(error::ErrorInternalServerError("user not found"))
-        .and_then(|user_bytes| {
-            bincode::deserialize(&user_bytes).map_err(|e| error::ErrorInternalServerError(e))
-        })?;
+        .ok_or(error::ErrorInternalServerError("user not found"))?;
 
     auth_user!(u, form_data.password);
 
+    let token =
+        Session::create(&db, &u.username).map_err(|e| error::ErrorInternalServerError(e))?;
+
+    // Credit any paid Lightning invoices. On-chain deposits are credited by the deposit watcher,
+    // so users can still log in while LND is unreachable
+    if let Some(lnd) = lnd.get_ref() {
+        if let Err(e) = lnd.sync_invoices(&db, &u.username, &cfg).await {
+            eprintln!("lightning error: {}", e);
+        }
+    }
+
     (if u
-        .get_account_balance(&**btcapi)
-        .await
-        .map_err(|e| error::ErrorPaymentRequired(e))?
-        >= MONTHLY_BTC
+        .has_paid_for_month(&db)
+        .map_err(|e| error::ErrorInternalServerError(e))?
         || u.username == "admin"
     {
         // Show the user the feed
-        load_feed(&mut db, 0).await
+        load_feed(&mut db, &cfg, 0, &token, &u.username).await
     } else {
-        load_account_overview(u, &**btcapi).await
+        load_account_overview(u, &token, &db, lnd.get_ref().as_ref(), &cfg).await
     })
-    .map(|res| {
+    .and_then(|res| {
         let mut resp = HttpResponse::build(StatusCode::OK)
             .content_type("text/html; charset=utf-8")
             .body(res);
 
-        resp.add_cookie(&Cookie::new("username", form_data.username))
-            .unwrap();
-        resp.add_cookie(&Cookie::new("password", form_data.password))
-            .unwrap();
+        resp.add_cookie(&Session::cookie(token))
+            .map_err(|e| error::ErrorInternalServerError(e))?;
 
-        resp
+        Ok(resp)
     })
 }
 
@@ -199,7 +254,9 @@ pub async fn login(
 #[post("/register")]
 pub async fn register(
     db_arc: web::Data<Db>,
-    btcapi: web::Data<RpcConnection<'_>>,
+    cfg: web::Data<Config>,
+    btcapi: web::Data<Arc<dyn PaymentBackend>>,
+    lnd: web::Data<Option<Lnd>>,
     form: web::Form<Registration>,
 ) -> impl Responder {
     // Sled lets us clone db very cheaply, and a clone will reference the same underlying db.
@@ -208,202 +265,315 @@ pub async fn register(
     let mut u = User::new(form_data.username.clone(), form_data.password.clone())
         .map_err(|e| e.to_string())
         .map_err(|e| error::ErrorInternalServerError(e))?;
-    u.generate_new_acc_address(&**btcapi)
-        .await
-        .map_err(|e| error::ErrorInternalServerError(e))?;
 
-    // The user is already signed up
-    if db
-        .contains_key(
-            bincode::serialize(&u.username).map_err(|e| error::ErrorInternalServerError(e))?,
-        )
+    // Don't mint addresses for names that are already taken
+    if User::load(&db, &u.username)
         .map_err(|e| error::ErrorInternalServerError(e))?
+        .is_some()
     {
         return Err(error::ErrorUnauthorized("user already exists"));
     }
+    u.generate_new_acc_address(btcapi.get_ref().as_ref(), &cfg)
+        .await?;
 
-    u.commit(&mut db)
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+    // The user signed up while the address was generated
+    if !u
+        .register(&db)
+        .map_err(|e| error::ErrorInternalServerError(e))?
+    {
+        return Err(error::ErrorUnauthorized("user already exists"));
+    }
+
+    let token =
+        Session::create(&db, &u.username).map_err(|e| error::ErrorInternalServerError(e))?;
 
     (if u
-        .get_account_balance(&**btcapi)
-        .await
-        .map_err(|e| error::ErrorPaymentRequired(e))?
-        >= MONTHLY_BTC
+        .has_paid_for_month(&db)
+        .map_err(|e| error::ErrorInternalServerError(e))?
     {
         // Show the user the feed
-        load_feed(&mut db, 0).await
+        load_feed(&mut db, &cfg, 0, &token, &u.username).await
     } else {
-        load_account_overview(u, &**btcapi).await
+        load_account_overview(u, &token, &db, lnd.get_ref().as_ref(), &cfg).await
     })
-    .map(|str_html| {
+    .and_then(|str_html| {
         let mut resp = HttpResponse::build(StatusCode::OK)
-            .header(http::header::LOCATION, "/account_overview.html")
+            .insert_header((http::header::LOCATION, "/account_overview.html"))
             .content_type("text/html; charset=utf-8")
             .body(str_html);
-        resp.add_cookie(&Cookie::new("username", form_data.username))
-            .unwrap();
-        resp.add_cookie(&Cookie::new("password", form_data.password))
-            .unwrap();
-        resp
+        resp.add_cookie(&Session::cookie(token))
+            .map_err(|e| error::ErrorInternalServerError(e))?;
+
+        Ok(resp)
     })
 }
 
 #[get("/account_overview.html")]
 pub async fn account_overview(
     db_arc: web::Data<Db>,
-    btcapi: web::Data<RpcConnection<'_>>,
-    req: HttpRequest,
+    cfg: web::Data<Config>,
+    lnd: web::Data<Option<Lnd>>,
+    sess: Authenticated,
 ) -> Result<HttpResponse, ActixError> {
-    let db = (**db_arc).clone();
-
-    let username = req
-        .cookie("username")
-        .ok_or(error::ErrorBadRequest("no username".to_owned()))?
-        .to_string();
-    let username_bytes: Vec<u8> =
-        bincode::serialize::<str>(username.split("=").skip(1).collect::<Vec<&str>>()[0])
-            .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
-
-    // Get the entry for the user indicated by the username cookie
-    let u: User = db
-        .get(username_bytes)
-        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
-        .ok_or(error::ErrorInternalServerError(
-            "user doesn't exist".to_owned(),
-        ))
-        .and_then(|u_bytes| {
-            bincode::deserialize(u_bytes.to_vec().as_slice())
-                .map_err(|e| error::ErrorInternalServerError(e.to_string()))
-        })?;
-
-    auth_user!(
-        u,
-        req.cookie("password")
-            .ok_or(error::ErrorInternalServerError("no password"))?
-            .to_string()
-            .split("=")
-            .skip(1)
-            .collect::<Vec<&str>>()[0]
-    );
-
     // Show the user their page
-    load_account_overview(u, &btcapi).await.map(|text_resp| {
+    load_account_overview(
+        sess.user,
+        &sess.token,
+        &db_arc,
+        lnd.get_ref().as_ref(),
+        &cfg,
+    )
+    .await
+    .map(|text_resp| {
         HttpResponse::build(StatusCode::OK)
             .content_type("text/html; charset=utf-8")
             .body(text_resp)
     })
 }
 
-#[get("/new_wallet")]
+/// Gives the user a new deposit address once every address they hold has been paid to. Until
+/// then, their page is shown with the unused ones instead.
+#[post("/new_wallet")]
 pub async fn new_wallet(
     db_arc: web::Data<Db>,
-    btcapi: web::Data<RpcConnection<'_>>,
-    req: HttpRequest,
+    cfg: web::Data<Config>,
+    btcapi: web::Data<Arc<dyn PaymentBackend>>,
+    lnd: web::Data<Option<Lnd>>,
+    sess: Authenticated,
+    form: web::Form<CsrfForm>,
 ) -> Result<HttpResponse, ActixError> {
-    let mut db = (**db_arc).clone();
+    sess.check_csrf(&db_arc, &form.csrf_token)?;
 
-    let username = req
-        .cookie("username")
-        .ok_or(error::ErrorBadRequest("no username".to_owned()))?
-        .to_string();
-    let username_bytes: Vec<u8> =
-        bincode::serialize::<str>(username.split("=").skip(1).collect::<Vec<&str>>()[0])
-            .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
-
-    // Get the entry for the user indicated by the username cookie
-    let mut u: User = db
-        .get(username_bytes)
-        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
-        .ok_or(error::ErrorInternalServerError(
-            "user doesn't exist".to_owned(),
-        ))
-        .and_then(|u_bytes| {
-            bincode::deserialize(u_bytes.to_vec().as_slice())
-                .map_err(|e| error::ErrorInternalServerError(e.to_string()))
-        })?;
-
-    auth_user!(
-        u,
-        req.cookie("password")
-            .ok_or(error::ErrorInternalServerError("no password"))?
-            .to_string()
-            .split("=")
-            .skip(1)
-            .collect::<Vec<&str>>()[0]
-    );
+    let db = (**db_arc).clone();
+    let mut u = sess.user;
 
-    u.generate_new_acc_address(&**btcapi)
+    if u.unused_addresses(&db)
+        .map_err(|e| error::ErrorInternalServerError(e))?
+        .is_empty()
+    {
+        let addr = u
+            .generate_new_acc_address(btcapi.get_ref().as_ref(), &cfg)
+            .await?;
+
+        // Other requests may have added addresses while this one was generated
+        u = User::add_address(&db, &u.username, &addr, cfg.max_unused_addresses)
+            .map_err(|e| error::ErrorInternalServerError(e))?;
+    }
+
+    // Show the user their page with the new wallet added
+    load_account_overview(u, &sess.token, &db, lnd.get_ref().as_ref(), &cfg)
         .await
+        .map(|text_resp| {
+            HttpResponse::build(StatusCode::OK)
+                .insert_header((http::header::LOCATION, "/account_overview.html"))
+                .content_type("text/html; charset=utf-8")
+                .body(text_resp)
+        })
+}
+
+/// Renders a QR code for one of the user's deposit addresses, requesting the amount still needed
+/// for another subscription period, so that it can be scanned without JavaScript.
+#[get("/qr/{address}.png")]
+pub async fn deposit_qr(
+    db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
+    info: Path<String>,
+    sess: Authenticated,
+) -> Result<HttpResponse, ActixError> {
+    let address = info.into_inner();
+
+    // Nobody else's addresses can be looked up
+    if !sess.user.btc_addresses.contains(&address) {
+        return Err(error::ErrorNotFound("no such deposit address"));
+    }
+
+    let sub = Subscription::load(&db_arc, &sess.user.username)
         .map_err(|e| error::ErrorInternalServerError(e))?;
-    u.commit(&mut db)
+    let due = cfg.monthly_price().saturating_sub(sub.credit);
+
+    qr::png(&cfg.payment_backend.payment_uri(&address, due))
+        .map(|png| {
+            HttpResponse::build(StatusCode::OK)
+                .content_type("image/png")
+                .insert_header((http::header::CACHE_CONTROL, "private, no-cache"))
+                .body(png)
+        })
+        .map_err(|e| error::ErrorInternalServerError(e))
+}
+
+/// Issues the user a Lightning invoice for one subscription period, and shows it on their page.
+#[post("/new_invoice")]
+pub async fn new_invoice(
+    db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
+    lnd: web::Data<Option<Lnd>>,
+    sess: Authenticated,
+    form: web::Form<CsrfForm>,
+) -> Result<HttpResponse, ActixError> {
+    sess.check_csrf(&db_arc, &form.csrf_token)?;
+
+    let lnd = lnd
+        .get_ref()
+        .as_ref()
+        .ok_or(error::ErrorNotFound("lightning invoices are not enabled"))?;
+
+    lnd.new_invoice(&db_arc, &sess.user.username, &cfg)
+        .await
         .map_err(|e| error::ErrorInternalServerError(e))?;
 
-    // Show the user their page with the new wallet added
-    load_account_overview(u, &btcapi).await.map(|text_resp| {
-        HttpResponse::build(StatusCode::OK)
-            .header(http::header::LOCATION, "/account_overview.html")
-            .content_type("text/html; charset=utf-8")
-            .body(text_resp)
-    })
+    load_account_overview(sess.user, &sess.token, &db_arc, Some(lnd), &cfg)
+        .await
+        .map(|text_resp| {
+            HttpResponse::build(StatusCode::OK)
+                .insert_header((http::header::LOCATION, "/account_overview.html"))
+                .content_type("text/html; charset=utf-8")
+                .body(text_resp)
+        })
+}
+
+/// Ends the current session and sends the user back to the login page.
+#[post("/logout")]
+pub async fn logout(db_arc: web::Data<Db>, sess: Authenticated) -> Result<HttpResponse> {
+    Session::revoke(&db_arc, &sess.token).map_err(|e| error::ErrorInternalServerError(e))?;
+
+    logged_out()
+}
+
+/// Ends every session belonging to the user, on every device.
+#[post("/logout_all")]
+pub async fn logout_all(db_arc: web::Data<Db>, sess: Authenticated) -> Result<HttpResponse> {
+    Session::revoke_all(&db_arc, &sess.user.username)
+        .map_err(|e| error::ErrorInternalServerError(e))?;
+
+    logged_out()
+}
+
+/// Shows the login page and clears the session cookie.
+fn logged_out() -> Result<HttpResponse> {
+    let mut resp = HttpResponse::build(StatusCode::OK)
+        .insert_header((http::header::LOCATION, "/login.html"))
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("../static/login.html"));
+    resp.add_cookie(&Session::removal_cookie())
+        .map_err(|e| error::ErrorInternalServerError(e))?;
+
+    Ok(resp)
 }
 
 /// Loads an indvidiaul picture / video from a post.
+///
+/// Stored links only work for the session they were issued to. Requests without a Range header
+/// use them up, but video players ask for ranges after the first response, so those are served
+/// until the link expires. Signed links only work for the session they were issued to, and only
+/// until they expire or the subscription they were issued under runs out, but are used as often
+/// as the session likes in the meantime. They are checked without touching the database.
 #[get("/posts/{post_id}")]
-pub async fn load_post(db_arc: web::Data<Db>, info: Path<String>) -> Result<afs::NamedFile> {
-    // posts are referenced by their bincode UID reprs
-    let post_uid =
-        bincode::serialize(info.0.as_str()).map_err(|e| error::ErrorInternalServerError(e))?;
-    let post_src: PostHist = bincode::deserialize(
-        db_arc
-            .get(&post_uid)
-            .map_err(|e| error::ErrorInternalServerError(e))?
-            .ok_or(error::ErrorNotFound("content does not exist"))?
-            .to_vec()
-            .as_slice(),
-    )
-    .map_err(|e| error::ErrorInternalServerError(e))?;
+pub async fn load_post(
+    req: HttpRequest,
+    db_arc: web::Data<Db>,
+    cfg: web::Data<Config>,
+    info: Path<String>,
+) -> Result<afs::NamedFile> {
+    let follow_err = |e| match e {
+        FollowError::Unknown => error::ErrorNotFound("content does not exist"),
+        FollowError::OtherSession => {
+            error::ErrorForbidden("this link was issued to another session")
+        }
+        FollowError::Expired => error::ErrorGone("this link has expired"),
+        FollowError::Used => error::ErrorGone("this link has already been used"),
+        FollowError::Storage(e) => error::ErrorInternalServerError(e),
+    };
 
-    if SystemTime::now() - Duration::new(60, 0) > post_src.created_at {
-        db_arc
-            .remove(post_uid)
-            .map_err(|e| error::ErrorInternalServerError(e))?;
+    match cfg.link_mode {
+        LinkMode::Stored => {
+            let sess = Authenticated::extract(&req).await?;
+            let whole = !req.headers().contains_key(http::header::RANGE);
 
-        return Err(error::ErrorNotFound("content not found"));
-    }
+            let path: PathBuf = links::follow(
+                &db_arc,
+                &info,
+                &sess.token,
+                whole,
+                cfg.link_lifetime(),
+                SystemTime::now(),
+            )
+            .map_err(follow_err)?
+            .path
+            .into();
+
+            // The link is only used up once the content can actually be sent
+            let file = afs::NamedFile::open(path)?;
+            if whole {
+                links::mark_delivered(&db_arc, &info).map_err(follow_err)?;
+            }
+
+            Ok(file)
+        }
+        LinkMode::Signed => {
+            let token = req
+                .cookie(SESSION_COOKIE)
+                .ok_or(error::ErrorUnauthorized("not logged in"))?;
+            let claims = signed_links::verify(&cfg, &info, token.value(), SystemTime::now())
+                .map_err(|e| match e {
+                    VerifyError::Malformed | VerifyError::BadSignature => {
+                        error::ErrorNotFound("content does not exist")
+                    }
+                    VerifyError::Expired => error::ErrorGone("this link has expired"),
+                    VerifyError::OtherSession => {
+                        error::ErrorForbidden("this link was issued to another session")
+                    }
+                })?;
 
-    Ok(afs::NamedFile::open(post_src.path)?)
+            Ok(afs::NamedFile::open(
+                cfg.content_folder.join(claims.content),
+            )?)
+        }
+    }
 }
 
 /// Responds to a user request with a oawefjoiawjfoiaewfjcustomized template of the feed.
 /// i: the index of the content to start on
-pub async fn load_feed(db: &mut Db, i: usize) -> Result<String> {
+/// session: the token of the session links to the content are issued to
+/// username: the user whose subscription signed links to the content end with
+pub async fn load_feed(
+    db: &mut Db,
+    cfg: &Config,
+    i: usize,
+    session: &str,
+    username: &str,
+) -> Result<String> {
+    let now = SystemTime::now();
+    let paid_until = match cfg.link_mode {
+        LinkMode::Signed if username != "admin" => Some(
+            Subscription::load(db, username)
+                .map_err(|e| error::ErrorInternalServerError(e))?
+                .paid_until,
+        ),
+        _ => None,
+    };
     let mut identifiers = Vec::new();
-    let real_sources = fs::read_dir(ITEM_PATH).map_err(|e| error::ErrorInternalServerError(e))?;
-
-    // Register single-use IDs for the content we want to serve to the user
-    for j in i..(i + ITEMS_PER_PAGE) {
-        // Store a mapping between the random identifier and the content to be served
-        let id = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(9)
-            .map(char::from)
-            .collect();
-        db.insert(
-            bincode::serialize(&id).map_err(|e| error::ErrorInternalServerError(e))?,
-            bincode::serialize(&j).map_err(|e| error::ErrorInternalServerError(e))?,
-        )
-        .map_err(|e| error::ErrorInternalServerError(e))?;
-        identifiers.push(id);
+    let real_sources =
+        fs::read_dir(&cfg.content_folder).map_err(|e| error::ErrorInternalServerError(e))?;
+    let captions = store::posts(db).map_err(|e| error::ErrorInternalServerError(e))?;
+
+    // Generate single-use IDs for the content we want to serve to the user
+    for _ in 0..cfg.items_per_page {
+        identifiers.push(
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(9)
+                .map(char::from)
+                .collect(),
+        );
     }
 
     FeedTemplate {
-        i: i,
+        i,
         posts: real_sources
             .filter_map(Result::ok)
             .enumerate()
-            .filter(|(index, _source)| *index >= (i * ITEMS_PER_PAGE))
-            .take(ITEMS_PER_PAGE)
+            .filter(|(index, _source)| *index >= (i * cfg.items_per_page))
+            .take(cfg.items_per_page)
             .zip(identifiers)
             .map(|((_i, source), mut uid): ((_, _), String)| {
                 source
@@ -417,43 +587,32 @@ pub async fn load_feed(db: &mut Db, i: usize) -> Result<String> {
                             uid += ".jpg"
                         }
 
-                        bincode::serialize(path_str)
+                        let link = match cfg.link_mode {
+                            // Store a mapping between the random identifier and the content to
+                            // be served
+                            LinkMode::Stored => {
+                                links::issue(db, &uid, path_str, session).map(|_| uid)
+                            }
+                            LinkMode::Signed => source
+                                .file_name()
+                                .to_str()
+                                .ok_or_else(|| "malformed path".to_owned())
+                                .and_then(|name| {
+                                    signed_links::sign(cfg, name, session, paid_until, now)
+                                }),
+                        };
+
+                        link.and_then(|link| Ok((link, captions.get(path_str)?)))
                             .map_err(|e| error::ErrorInternalServerError(e))
-                            .and_then(|ser_path| {
-                                bincode::serialize(&uid)
-                                    .map_err(|e| error::ErrorInternalServerError(e))
-                                    .and_then(|uid_bytes| {
-                                        bincode::serialize(&PostHist {
-                                            path: path_str.to_owned(),
-                                            created_at: SystemTime::now(),
-                                        })
-                                        .map_err(|e| error::ErrorInternalServerError(e))
-                                        .and_then(
-                                            |ser_post| {
-                                                db.insert(uid_bytes, ser_post)
-                                                    .map_err(|e| error::ErrorInternalServerError(e))
-                                                    .map(|_| ser_path)
-                                            },
-                                        )
+                            .and_then(|(link, caption)| {
+                                caption
+                                    .ok_or(error::ErrorInternalServerError("no entry"))
+                                    .map(|caption| Post {
+                                        isvideo: path_str.contains("mov"),
+                                        caption,
+                                        src: format!("/posts/{}", link),
                                     })
                             })
-                            .and_then(|ser_path| {
-                                db.get(ser_path)
-                                    .map_err(|e| error::ErrorInternalServerError(e))
-                                    .and_then(|possible_hit| {
-                                        possible_hit
-                                            .ok_or(error::ErrorInternalServerError("no entry"))
-                                    })
-                            })
-                            .and_then(|db_entry| {
-                                bincode::deserialize(&db_entry)
-                                    .map_err(|e| error::ErrorInternalServerError(e))
-                            })
-                            .map(|caption: String| Post {
-                                isvideo: path_str.contains("mov"),
-                                caption,
-                                src: format!("/posts/{}", uid),
-                            })
                             .map_err(|e| error::ErrorInternalServerError(e))
                     })
             })
@@ -463,20 +622,713 @@ pub async fn load_feed(db: &mut Db, i: usize) -> Result<String> {
     .map_err(|e| error::ErrorInternalServerError(e))
 }
 
-/// Loads the user's account overview.
-pub async fn load_account_overview(u: User, adapter: &RpcConnection<'_>) -> Result<String> {
-    let balance = u
-        .get_account_balance(adapter)
-        .await
+/// Loads the user's account overview, with their deposits as of the deposit watcher's last sync.
+pub async fn load_account_overview(
+    u: User,
+    token: &str,
+    db: &Db,
+    lnd: Option<&Lnd>,
+    cfg: &Config,
+) -> Result<String> {
+    // Paid invoices are credited before the subscription is shown. While LND is unreachable,
+    // the account is shown without Lightning rather than not at all
+    let (lightning, invoice) = match lnd {
+        Some(lnd) => match lnd.sync_invoices(db, &u.username, cfg).await {
+            Ok(invoice) => (true, invoice),
+            Err(e) => {
+                eprintln!("lightning error: {}", e);
+
+                (false, None)
+            }
+        },
+        None => (false, None),
+    };
+
+    let sub =
+        Subscription::load(db, &u.username).map_err(|e| error::ErrorInternalServerError(e))?;
+    let pending = ledger::pending_deposits(db, &u.username)
         .map_err(|e| error::ErrorInternalServerError(e))?;
 
     // Instantiate the account overview template
     OverviewTemplate {
         username: &u.username,
-        balance_insufficient: balance < MONTHLY_BTC,
-        balance: balance,
-        account_wallets: u.btc_addresses.into_iter().map(|addr| addr).collect(),
+        subscription_inactive: !sub.is_active(),
+        credit: cfg.payment_backend.format_amount(sub.credit),
+        monthly_price: cfg.payment_backend.format_amount(cfg.monthly_price()),
+        currency: cfg.payment_backend.currency(),
+        currency_name: cfg.payment_backend.currency_name(),
+        paid_until: sub.paid_until_str(),
+        account_wallets: u
+            .unused_addresses(db)
+            .map_err(|e| error::ErrorInternalServerError(e))?,
+        csrf_token: Session::csrf_token(db, token)
+            .map_err(|e| error::ErrorInternalServerError(e))?,
+        pending_deposits: pending
+            .into_iter()
+            .map(|deposit| PendingView {
+                amount: cfg.payment_backend.format_amount(deposit.amount),
+                confirmations: deposit.confirmations,
+            })
+            .collect(),
+        min_confirmations: cfg.min_confirmations,
+        lightning,
+        invoice: invoice.map(InvoiceView::new).transpose()?,
     }
     .render()
     .map_err(|e| error::ErrorInternalServerError(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        amount::Amount,
+        links::LinkMetrics,
+        mock_rpc::{MockBitcoind, MockLnd},
+        session,
+        watcher::WatchSignal,
+    };
+
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    use std::time::Duration;
+
+    /// A store with a temporary database, content folder, mock node and mock LND node.
+    struct TestStore {
+        db: Db,
+        cfg: Config,
+        node: MockBitcoind,
+        lnd: MockLnd,
+    }
+
+    impl TestStore {
+        /// Creates a store holding the given number of captioned posts, two to a page.
+        fn new(posts: usize) -> Self {
+            let content =
+                std::env::temp_dir().join(format!("onionfans-content-{}", rand::random::<u64>()));
+            fs::create_dir_all(&content).unwrap();
+
+            let db = sled::Config::new().temporary(true).open().unwrap();
+            let captions = store::posts(&db).unwrap();
+            for i in 0..posts {
+                let path = content.join(format!("{}.jpg", i));
+                fs::write(&path, "not really a jpeg").unwrap();
+                captions
+                    .insert(path.to_str().unwrap(), &format!("caption {}", i))
+                    .unwrap();
+            }
+
+            Self {
+                db,
+                cfg: Config {
+                    wallet_address: "bcrt1qstore".to_owned(),
+                    admin_pass: "hunter2".to_owned(),
+                    content_folder: content,
+                    items_per_page: 2,
+                    ..Config::default()
+                },
+                node: MockBitcoind::start(),
+                lnd: MockLnd::start(),
+            }
+        }
+
+        /// Credits deposits to the mock node, as the deposit watcher would.
+        async fn sync(&self) {
+            ledger::sync_all(&self.db, &self.node.backend(), &self.cfg)
+                .await
+                .unwrap();
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.cfg.content_folder);
+        }
+    }
+
+    /// Serves the store's routes.
+    macro_rules! test_app {
+        ($store:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new($store.db.clone()))
+                    .app_data(web::Data::new($store.cfg.clone()))
+                    .app_data(web::Data::new(
+                        Arc::new($store.node.backend()) as Arc<dyn PaymentBackend>
+                    ))
+                    .app_data(web::Data::new(Some($store.lnd.backend())))
+                    .app_data(web::Data::new(WatchSignal::default()))
+                    .app_data(web::Data::new(LinkMetrics::default()))
+                    .configure(crate::routes),
+            )
+            .await
+        };
+    }
+
+    fn form(uri: &str, username: &str, password: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .set_form(&[("username", username), ("password", password)])
+    }
+
+    /// Posts a form carrying only the given CSRF token.
+    fn post_csrf(uri: &str, csrf: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .set_form(&[("csrf_token", csrf)])
+    }
+
+    /// Finds the CSRF token in a page's forms.
+    fn csrf_token(page: &str) -> String {
+        page.split("name=\"csrf_token\" value=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("no CSRF token on the page")
+            .to_owned()
+    }
+
+    fn body_str(body: &[u8]) -> String {
+        String::from_utf8_lossy(body).into_owned()
+    }
+
+    /// Gets the /posts/ links of every post in a feed page.
+    fn post_links(feed: &str) -> Vec<String> {
+        feed.split("src=\"")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .filter(|src| src.starts_with("/posts/"))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn test_register() {
+        let store = TestStore::new(0);
+        let app = test_app!(store);
+
+        let resp = test::call_service(&app, form("/register", "lol", "hunter2").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(session::cookie_from(&resp).is_some());
+
+        // Nothing has been paid yet, so the user is asked for a deposit
+        let body = body_str(&test::read_body(resp).await);
+        assert!(body.contains("Your subscription has lapsed"));
+
+        let u = User::load(&store.db, "lol").unwrap().unwrap();
+        assert_eq!(u.btc_addresses.len(), 1);
+        assert!(body.contains(u.btc_addresses.iter().next().unwrap().as_str()));
+
+        // The same username can't be registered twice, and trying doesn't mint addresses
+        for _ in 0..3 {
+            let resp =
+                test::call_service(&app, form("/register", "lol", "hunter3").to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(store.node.wallet().addresses.len(), 1);
+        assert!(argon2::verify_encoded(
+            &User::load(&store.db, "lol").unwrap().unwrap().password_hash,
+            b"hunter2"
+        )
+        .unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_login() {
+        let store = TestStore::new(0);
+        let app = test_app!(store);
+        test::call_service(&app, form("/register", "lol", "hunter2").to_request()).await;
+
+        let resp = test::call_service(&app, form("/login", "lol", "hunter1").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(session::cookie_from(&resp).is_none());
+
+        let resp = test::call_service(&app, form("/login", "lol", "hunter2").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(body_str(&test::read_body(resp).await).contains("Your subscription has lapsed"));
+
+        // An unconfirmed deposit is shown, but not credited
+        let u = User::load(&store.db, "lol").unwrap().unwrap();
+        store.node.wallet().deposit(
+            u.btc_addresses.iter().next().unwrap(),
+            store.cfg.monthly_btc,
+            0,
+        );
+        store.sync().await;
+
+        let resp = test::call_service(&app, form("/login", "lol", "hunter2").to_request()).await;
+        let body = body_str(&test::read_body(resp).await);
+        assert!(body.contains("Your subscription has lapsed"));
+        assert!(body.contains("0.00020000 BTC (0 of 1 confirmations)"));
+
+        // Once it confirms, the deposit of the monthly price unlocks the feed
+        store.node.wallet().utxos[0].confirmations = 1;
+        store.sync().await;
+
+        let resp = test::call_service(&app, form("/login", "lol", "hunter2").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(session::cookie_from(&resp).is_some());
+        assert!(body_str(&test::read_body(resp).await).contains("<title>Feed"));
+    }
+
+    #[actix_rt::test]
+    async fn test_feed_pagination() {
+        let store = TestStore::new(3);
+        let app = test_app!(store);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/feed/0.html").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, form("/register", "lol", "hunter2").to_request()).await;
+        let cookie = session::cookie_from(&resp).unwrap();
+
+        let mut captions = Vec::new();
+        for (page, expected) in [(0, 2), (1, 1), (2, 0)].iter() {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri(&format!("/feed/{}.html", page))
+                    .cookie(cookie.clone())
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let body = body_str(&test::read_body(resp).await);
+            assert_eq!(post_links(&body).len(), *expected);
+            captions.extend((0..3).filter(|i| body.contains(&format!("caption {}", i))));
+        }
+
+        // Every post appears on exactly one page
+        captions.sort();
+        assert_eq!(captions, vec![0, 1, 2]);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_links_vanish() {
+        let store = TestStore::new(1);
+        let app = test_app!(store);
+
+        let resp = test::call_service(&app, form("/register", "lol", "hunter2").to_request()).await;
+        let cookie = session::cookie_from(&resp).unwrap();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/feed/0.html")
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        let link = post_links(&body_str(&test::read_body(resp).await)).remove(0);
+        let follow = || test::TestRequest::get().uri(&link).cookie(cookie.clone());
+
+        // Only the session the link was issued to can follow it
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&link).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp =
+            test::call_service(&app, form("/register", "rofl", "hunter2").to_request()).await;
+        let other = session::cookie_from(&resp).unwrap();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&link)
+                .cookie(other)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Content that can't be opened doesn't use the link up
+        let links = store::links(&store.db).unwrap();
+        let uid = link.trim_start_matches("/posts/");
+        let path = links.get(uid).unwrap().unwrap().path;
+        let moved = format!("{}.moved", path);
+        fs::rename(&path, &moved).unwrap();
+        let resp = test::call_service(&app, follow().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        fs::rename(&moved, &path).unwrap();
+
+        let resp = test::call_service(&app, follow().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(&test::read_body(resp).await[..], b"not really a jpeg");
+
+        // The content is only sent in full once, but players can still ask for ranges of it
+        let resp = test::call_service(&app, follow().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        let resp = test::call_service(
+            &app,
+            follow()
+                .insert_header((http::header::RANGE, "bytes=0-5"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&test::read_body(resp).await[..], b"not re");
+
+        // Age the link past its lifetime
+        let mut hist = links.get(uid).unwrap().unwrap();
+        hist.created_at = SystemTime::now() - store.cfg.link_lifetime() - Duration::from_secs(1);
+        links.insert(uid, &hist).unwrap();
+
+        let resp = test::call_service(
+            &app,
+            follow()
+                .insert_header((http::header::RANGE, "bytes=0-5"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        assert!(links.get(uid).unwrap().is_none());
+
+        // Links that never existed, or that have since been forgotten, don't exist
+        let resp = test::call_service(&app, follow().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/posts/bogus.jpg")
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_signed_post_links() {
+        let mut store = TestStore::new(1);
+        store.cfg.link_mode = LinkMode::Signed;
+        store.cfg.link_keys = vec![base64::encode([1; 32])];
+        let app = test_app!(store);
+
+        // Registers the user, paying for a month if asked, and gets the link to the first post
+        // on their feed
+        let feed_link = |username: &'static str, pay: bool| {
+            let (app, store) = (&app, &store);
+            async move {
+                let resp =
+                    test::call_service(app, form("/register", username, "hunter2").to_request())
+                        .await;
+                let cookie = session::cookie_from(&resp).unwrap();
+                if pay {
+                    let u = User::load(&store.db, username).unwrap().unwrap();
+                    store.node.wallet().deposit(
+                        u.btc_addresses.iter().next().unwrap(),
+                        store.cfg.monthly_btc,
+                        1,
+                    );
+                    store.sync().await;
+                }
+                let resp = test::call_service(
+                    app,
+                    test::TestRequest::get()
+                        .uri("/feed/0.html")
+                        .cookie(cookie.clone())
+                        .to_request(),
+                )
+                .await;
+
+                (
+                    cookie,
+                    post_links(&body_str(&test::read_body(resp).await)).remove(0),
+                )
+            }
+        };
+
+        let (cookie, link) = feed_link("lol", true).await;
+        let follow = |link: &str| {
+            test::TestRequest::get()
+                .uri(link)
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        // Rendering the feed wrote nothing
+        assert!(store::links(&store.db).unwrap().tree().is_empty());
+
+        // The session can follow the link until it expires, as often as it likes
+        for _ in 0..2 {
+            let resp = test::call_service(&app, follow(&link)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(&test::read_body(resp).await[..], b"not really a jpeg");
+        }
+
+        // But no other session can, even of the same user
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&link).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, form("/login", "lol", "hunter2").to_request()).await;
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&link)
+                .cookie(session::cookie_from(&resp).unwrap())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let (other, other_link) = feed_link("rofl", false).await;
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&link)
+                .cookie(other.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Links issued to users who haven't paid end with their subscription, so never work
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&other_link)
+                .cookie(other)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+
+        let tampered = format!("{}A", link);
+        let resp = test::call_service(&app, follow(&tampered)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Links keep working while the key that signed them is kept after its replacement
+        store.cfg.link_keys.insert(0, base64::encode([2; 32]));
+        let app = test_app!(store);
+        let resp = test::call_service(&app, follow(&link)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        store.cfg.link_keys.pop();
+        let app = test_app!(store);
+        let resp = test::call_service(&app, follow(&link)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_new_wallet() {
+        let store = TestStore::new(0);
+        let app = test_app!(store);
+
+        let resp = test::call_service(&app, post_csrf("/new_wallet", "").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, form("/register", "lol", "hunter2").to_request()).await;
+        let cookie = session::cookie_from(&resp).unwrap();
+        let csrf = csrf_token(&body_str(&test::read_body(resp).await));
+        let post_new_wallet = |csrf: &str| {
+            post_csrf("/new_wallet", csrf)
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        // Forms posted from anywhere but the store's own pages are refused
+        let resp = test::call_service(&app, post_new_wallet("forged")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/new_wallet")
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        assert_ne!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            User::load(&store.db, "lol")
+                .unwrap()
+                .unwrap()
+                .btc_addresses
+                .len(),
+            1
+        );
+
+        // While the user holds an unused address, it is handed back instead of a new one
+        let addr = User::load(&store.db, "lol")
+            .unwrap()
+            .unwrap()
+            .btc_addresses
+            .into_iter()
+            .next()
+            .unwrap();
+        for _ in 0..2 {
+            let resp = test::call_service(&app, post_new_wallet(&csrf)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(body_str(&test::read_body(resp).await).contains(addr.as_str()));
+        }
+        assert_eq!(
+            User::load(&store.db, "lol")
+                .unwrap()
+                .unwrap()
+                .btc_addresses
+                .len(),
+            1
+        );
+        assert_eq!(store.node.wallet().addresses.len(), 1);
+
+        // Once it is paid, it is rotated out for a new one
+        store
+            .node
+            .wallet()
+            .deposit(&addr, Amount::from_sat(10_000), 1);
+        store.sync().await;
+
+        let resp = test::call_service(&app, post_new_wallet(&csrf)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let u = User::load(&store.db, "lol").unwrap().unwrap();
+        assert_eq!(u.btc_addresses.len(), 2);
+        assert_eq!(User::deposit_addresses(&store.db).unwrap().len(), 2);
+
+        // Only the new address is shown, and it is labeled for the user
+        let body = body_str(&test::read_body(resp).await);
+        assert!(!body.contains(addr.as_str()));
+        let unused = u.unused_addresses(&store.db).unwrap();
+        assert_eq!(unused.len(), 1);
+        assert!(body.contains(unused[0]));
+        assert_eq!(store.node.wallet().addresses[unused[0]].0, "onionfans:lol");
+
+        let resp = test::call_service(&app, post_new_wallet(&csrf)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            User::load(&store.db, "lol")
+                .unwrap()
+                .unwrap()
+                .btc_addresses
+                .len(),
+            2
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_deposit_qr() {
+        let store = TestStore::new(0);
+        let app = test_app!(store);
+
+        let resp = test::call_service(&app, form("/register", "lol", "hunter2").to_request()).await;
+        let cookie = session::cookie_from(&resp).unwrap();
+        let body = body_str(&test::read_body(resp).await);
+        let addr = User::load(&store.db, "lol")
+            .unwrap()
+            .unwrap()
+            .btc_addresses
+            .into_iter()
+            .next()
+            .unwrap();
+        assert!(body.contains(&format!("src=\"/qr/{}.png\"", addr)));
+
+        let uri = format!("/qr/{}.png", addr);
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&uri)
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+        assert_eq!(&test::read_body(resp).await[..4], b"\x89PNG");
+
+        // Other users' addresses aren't rendered
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/qr/bcrt1qsomeoneelse.png")
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_lightning_invoice() {
+        let store = TestStore::new(0);
+        let app = test_app!(store);
+
+        let resp = test::call_service(&app, form("/register", "lol", "hunter2").to_request()).await;
+        let cookie = session::cookie_from(&resp).unwrap();
+        let body = body_str(&test::read_body(resp).await);
+        assert!(body.contains("action=\"/new_invoice\""));
+
+        let resp = test::call_service(
+            &app,
+            post_csrf("/new_invoice", &csrf_token(&body))
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The invoice is shown as text and as a QR code, and no new one is offered
+        let invoice = store.lnd.node().invoices[0].clone();
+        assert_eq!(invoice.value, store.cfg.monthly_btc.as_sat());
+        let body = body_str(&test::read_body(resp).await);
+        assert!(body.contains(&invoice.payment_request));
+        assert!(body.contains("src=\"data:image"));
+        assert!(!body.contains("action=\"/new_invoice\""));
+
+        // While LND is down, users can still log in and see their account, just not over
+        // Lightning
+        store.lnd.node().down = true;
+        let resp = test::call_service(&app, form("/login", "lol", "hunter2").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_str(&test::read_body(resp).await);
+        assert!(body.contains("Your subscription has lapsed"));
+        assert!(!body.contains(&invoice.payment_request));
+        assert!(!body.contains("action=\"/new_invoice\""));
+        store.lnd.node().down = false;
+
+        // Once paid, the next visit credits it
+        store.lnd.node().settle(&invoice.r_hash);
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/account_overview.html")
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        let body = body_str(&test::read_body(resp).await);
+        assert!(!body.contains("Your subscription has lapsed"));
+        assert!(Subscription::load(&store.db, "lol").unwrap().is_active());
+    }
+
+    #[actix_rt::test]
+    async fn test_new_post_requires_admin_pass() {
+        let store = TestStore::new(0);
+        let app = test_app!(store);
+        let post = |password: &str| {
+            test::TestRequest::post()
+                .uri("/new_post")
+                .set_json(&json!({
+                    "caption": "hello",
+                    "src": "/content/hello.jpg",
+                    "password": password,
+                }))
+                .to_request()
+        };
+        let captions = store::posts(&store.db).unwrap();
+
+        let resp = test::call_service(&app, post("hunter1")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(captions.get("/content/hello.jpg").unwrap().is_none());
+
+        let resp = test::call_service(&app, post("hunter2")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            captions.get("/content/hello.jpg").unwrap(),
+            Some("hello".to_owned())
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use super::{
+    amount::{Amount, BTC_DECIMALS, XMR_DECIMALS},
+    config::Config,
+    ingress::{OutPoint, RpcConnection, RpcError, Utxo, RPC_IN_WARMUP},
+    monero::MoneroWallet,
+    sweep::SweepPlan,
+};
+
+use actix_web::{http::StatusCode, ResponseError};
+use async_trait::async_trait;
+
+use std::{collections::HashSet, fmt, str::FromStr, sync::Arc};
+
+/// A payment to one of the store's deposit addresses, which may not have confirmed yet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Deposit {
+    /// The transaction paying the deposit, and the index of the payment within it
+    pub txid: String,
+    pub vout: u32,
+    pub address: Option<String>,
+    pub amount: Amount,
+    pub confirmations: u64,
+}
+
+impl Deposit {
+    /// Identifies the deposit uniquely, so that it is never credited twice.
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.txid, self.vout)
+    }
+
+    /// Determines whether or not the deposit has the store's required confirmations, and so can
+    /// be credited whatever its amount.
+    pub fn is_confirmed(&self, cfg: &Config) -> bool {
+        self.confirmations >= u64::from(cfg.min_confirmations)
+    }
+}
+
+impl From<&Utxo> for Deposit {
+    fn from(utxo: &Utxo) -> Self {
+        Self {
+            txid: utxo.txid.clone(),
+            vout: utxo.vout,
+            address: utxo.address.clone(),
+            amount: utxo.amount,
+            confirmations: utxo.confirmations,
+        }
+    }
+}
+
+/// An error encountered while talking to a payment backend.
+#[derive(Debug)]
+pub enum PaymentError {
+    /// The backend could not be reached, or isn't ready yet
+    Unavailable(String),
+
+    /// The backend is not configured correctly
+    Misconfigured(String),
+
+    /// The backend refused or failed the operation
+    Failed(String),
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(e) => write!(f, "payment backend unavailable: {}", e),
+            Self::Misconfigured(e) => write!(f, "payment backend misconfigured: {}", e),
+            Self::Failed(e) => write!(f, "payment backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PaymentError {}
+
+impl ResponseError for PaymentError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Misconfigured(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Failed(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl From<RpcError> for PaymentError {
+    fn from(e: RpcError) -> Self {
+        match e {
+            RpcError::Transport(_) => Self::Unavailable(e.to_string()),
+            RpcError::Rpc { code, .. } if code == RPC_IN_WARMUP => Self::Unavailable(e.to_string()),
+            RpcError::Auth(_) => Self::Misconfigured(e.to_string()),
+            RpcError::Http(..) | RpcError::Rpc { .. } | RpcError::Decode(_) => {
+                Self::Failed(e.to_string())
+            }
+        }
+    }
+}
+
+/// A sweep that failed part way through. The transactions broadcast before the failure can't be
+/// taken back, so their ids are kept for the run history.
+#[derive(Debug)]
+pub struct SweepError {
+    pub broadcast: Vec<String>,
+    pub error: PaymentError,
+}
+
+impl fmt::Display for SweepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.broadcast.is_empty() {
+            write!(f, "{}", self.error)
+        } else {
+            write!(
+                f,
+                "{} after broadcasting {}",
+                self.error,
+                self.broadcast.join(", ")
+            )
+        }
+    }
+}
+
+impl std::error::Error for SweepError {}
+
+/// Something that can take payments into deposit addresses and sweep them into the store's
+/// wallet. Users, the ledger and the payout scheduler only ever talk to a node through this.
+#[async_trait(?Send)]
+pub trait PaymentBackend {
+    /// Generates a new deposit address, labeled so that deposits can be attributed to a user.
+    async fn new_deposit_address(&self, label: &str) -> Result<String, PaymentError>;
+
+    /// Gets every deposit to the given addresses that hasn't been swept, including unconfirmed
+    /// ones.
+    async fn deposits(&self, addrs: &[&str]) -> Result<Vec<Deposit>, PaymentError>;
+
+    /// Plans a sweep of every deposit to the given addresses with the store's required
+    /// confirmations into the destination. Only deposits whose ids are among the credited ones
+    /// are swept, since swept deposits can no longer be credited.
+    async fn plan_sweep(
+        &self,
+        addrs: &[&str],
+        credited: &HashSet<String>,
+        destination: &str,
+    ) -> Result<SweepPlan, PaymentError>;
+
+    /// Builds the plan's transactions without broadcasting them, returning them for inspection.
+    async fn draft_sweep(&self, plan: &SweepPlan) -> Result<Vec<String>, PaymentError>;
+
+    /// Broadcasts the plan's transactions in order, returning their ids. Stops at the first that
+    /// fails, returning the ids of those broadcast before it with the error.
+    async fn execute_sweep(&self, plan: &SweepPlan) -> Result<Vec<String>, SweepError>;
+}
+
+/// The kinds of payment backend a store can take payments through.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// Bitcoin, through a bitcoind wallet
+    Bitcoind,
+
+    /// Monero, through monero-wallet-rpc
+    Monero,
+}
+
+impl BackendKind {
+    /// The ticker of the currency taken by the backend.
+    pub fn currency(self) -> &'static str {
+        match self {
+            Self::Bitcoind => "BTC",
+            Self::Monero => "XMR",
+        }
+    }
+
+    /// The name of the currency taken by the backend, for display.
+    pub fn currency_name(self) -> &'static str {
+        match self {
+            Self::Bitcoind => "Bitcoin",
+            Self::Monero => "Monero",
+        }
+    }
+
+    /// The number of decimal places in the currency taken by the backend. Amounts count its
+    /// smallest unit: satoshis, or piconero.
+    pub fn decimals(self) -> usize {
+        match self {
+            Self::Bitcoind => BTC_DECIMALS,
+            Self::Monero => XMR_DECIMALS,
+        }
+    }
+
+    /// Formats an amount of the currency taken by the backend, for display.
+    pub fn format_amount(self, amount: Amount) -> String {
+        amount.to_decimal(self.decimals())
+    }
+
+    /// A URI requesting the amount at the address, which wallets open when it is scanned: a
+    /// BIP21 bitcoin: URI, or its monero: equivalent.
+    pub fn payment_uri(self, address: &str, amount: Amount) -> String {
+        let amount = self.format_amount(amount);
+
+        match self {
+            Self::Bitcoind => format!("bitcoin:{}?amount={}", address, amount),
+            Self::Monero => format!("monero:{}?tx_amount={}", address, amount),
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bitcoind" => Ok(Self::Bitcoind),
+            "monero" => Ok(Self::Monero),
+            _ => Err(format!(
+                "unknown payment backend {:?}, expected bitcoind or monero",
+                s
+            )),
+        }
+    }
+}
+
+/// Connects to the payment backend configured for the store.
+// Backends hold HTTP clients tied to the thread that made them, so each worker connects its own
+#[allow(clippy::arc_with_non_send_sync)]
+pub fn from_config(cfg: &Config) -> Result<Arc<dyn PaymentBackend>, String> {
+    Ok(match cfg.payment_backend {
+        BackendKind::Bitcoind => Arc::new(Bitcoind::from_config(cfg)?),
+        BackendKind::Monero => Arc::new(MoneroWallet::from_config(cfg)?),
+    })
+}
+
+/// Takes payments in bitcoin through a bitcoind wallet.
+pub struct Bitcoind {
+    rpc: RpcConnection,
+
+    /// The type of deposit addresses to generate
+    address_type: String,
+
+    /// The confirmations a deposit needs before it is swept
+    min_confirmations: u32,
+
+    /// The confirmation target and bounds of the sweep's fee rate, in sat/vB
+    conf_target: u16,
+    min_fee_rate: u64,
+    max_fee_rate: u64,
+}
+
+impl Bitcoind {
+    /// Uses the given connection, with the store's address type and sweep fee settings.
+    pub fn new(rpc: RpcConnection, cfg: &Config) -> Self {
+        Self {
+            rpc,
+            address_type: cfg.deposit_address_type.clone(),
+            min_confirmations: cfg.min_confirmations,
+            conf_target: cfg.sweep_conf_target,
+            min_fee_rate: cfg.sweep_min_fee_rate,
+            max_fee_rate: cfg.sweep_max_fee_rate,
+        }
+    }
+
+    /// Connects to the node configured for the store.
+    pub fn from_config(cfg: &Config) -> Result<Self, String> {
+        Ok(Self::new(RpcConnection::from_config(cfg)?, cfg))
+    }
+
+    /// Picks a fee rate in sat/vB for the sweep, from bitcoind's estimate clamped to the
+    /// configured bounds. Falls back to the floor if bitcoind has too little data to estimate.
+    pub async fn fee_rate(&self) -> Result<u64, RpcError> {
+        let estimate = self.rpc.estimate_smart_fee(self.conf_target).await?;
+
+        Ok(match estimate {
+            // bitcoind estimates in BTC/kvB
+            Some(per_kvb) => per_kvb
+                .as_sat()
+                .div_ceil(1000)
+                .max(self.min_fee_rate)
+                .min(self.max_fee_rate),
+            None => self.min_fee_rate,
+        })
+    }
+
+    /// Gets every UTXO with at least minconf confirmations paying the given addresses, and
+    /// nothing else the wallet holds.
+    async fn utxos(&self, addrs: &[&str], minconf: u32) -> Result<Vec<Utxo>, RpcError> {
+        // listunspent treats an empty address filter as no filter at all
+        if addrs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .rpc
+            .list_unspent(minconf, addrs)
+            .await?
+            .into_iter()
+            .filter(|utxo| utxo.amount > Amount::ZERO)
+            .collect())
+    }
+}
+
+/// The outpoints spent by a sweep transaction's inputs.
+fn outpoints(inputs: &[Deposit]) -> Vec<OutPoint> {
+    inputs
+        .iter()
+        .map(|deposit| OutPoint {
+            txid: deposit.txid.clone(),
+            vout: deposit.vout,
+        })
+        .collect()
+}
+
+#[async_trait(?Send)]
+impl PaymentBackend for Bitcoind {
+    async fn new_deposit_address(&self, label: &str) -> Result<String, PaymentError> {
+        Ok(self.rpc.get_new_address(label, &self.address_type).await?)
+    }
+
+    async fn deposits(&self, addrs: &[&str]) -> Result<Vec<Deposit>, PaymentError> {
+        Ok(self
+            .utxos(addrs, 0)
+            .await?
+            .iter()
+            .map(Deposit::from)
+            .collect())
+    }
+
+    async fn plan_sweep(
+        &self,
+        addrs: &[&str],
+        credited: &HashSet<String>,
+        destination: &str,
+    ) -> Result<SweepPlan, PaymentError> {
+        let mut utxos = self.utxos(addrs, self.min_confirmations).await?;
+        utxos.retain(|utxo| credited.contains(&Deposit::from(utxo).id()));
+
+        Ok(SweepPlan::new(destination, &utxos, self.fee_rate().await?))
+    }
+
+    async fn draft_sweep(&self, plan: &SweepPlan) -> Result<Vec<String>, PaymentError> {
+        let mut txs = Vec::new();
+
+        for tx in &plan.txs {
+            txs.push(
+                self.rpc
+                    .create_raw_transaction(
+                        &outpoints(&tx.inputs),
+                        &[(plan.destination.clone(), tx.value)],
+                    )
+                    .await?,
+            );
+        }
+
+        Ok(txs)
+    }
+
+    async fn execute_sweep(&self, plan: &SweepPlan) -> Result<Vec<String>, SweepError> {
+        let mut txids = Vec::new();
+
+        for tx in &plan.txs {
+            match self
+                .rpc
+                .spend_utxos(&outpoints(&tx.inputs), &plan.destination, tx.value)
+                .await
+            {
+                Ok(txid) => txids.push(txid),
+                Err(e) => {
+                    return Err(SweepError {
+                        broadcast: txids,
+                        error: e.into(),
+                    })
+                }
+            }
+        }
+
+        Ok(txids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock_rpc::MockBitcoind;
+
+    fn ids(deposits: &[Deposit]) -> HashSet<String> {
+        deposits.iter().map(Deposit::id).collect()
+    }
+
+    #[actix_rt::test]
+    async fn test_sweep_only_deposits() {
+        let node = MockBitcoind::start();
+        let backend = node.backend();
+        node.wallet()
+            .deposit("bcrt1qunrelated", Amount::from_sat(1_000_000), 6);
+
+        // No deposit addresses doesn't mean sweep everything
+        assert!(backend.deposits(&[]).await.unwrap().is_empty());
+        assert!(backend
+            .plan_sweep(&[], &HashSet::new(), "bcrt1qstore")
+            .await
+            .unwrap()
+            .txs
+            .is_empty());
+
+        let addr = backend.new_deposit_address("onionfans:lol").await.unwrap();
+        node.wallet().deposit(&addr, Amount::from_sat(50_000), 6);
+        node.wallet().deposit(&addr, Amount::from_sat(10_000), 0);
+        let deposits = backend.deposits(&[&addr]).await.unwrap();
+        assert_eq!(deposits.len(), 2);
+        assert_eq!(
+            deposits
+                .iter()
+                .filter(|deposit| deposit.is_confirmed(&Config::default()))
+                .map(|deposit| deposit.amount)
+                .sum::<Amount>(),
+            Amount::from_sat(50_000)
+        );
+
+        // Deposits that haven't been credited are never swept
+        assert!(backend
+            .plan_sweep(&[&addr], &HashSet::new(), "bcrt1qstore")
+            .await
+            .unwrap()
+            .txs
+            .is_empty());
+
+        // The mock estimates 10 sat/vB
+        let plan = backend
+            .plan_sweep(&[&addr], &ids(&deposits), "bcrt1qstore")
+            .await
+            .unwrap();
+        assert_eq!(plan.fee_rate, 10);
+        assert_eq!(plan.txs.len(), 1);
+        let txids = backend.execute_sweep(&plan).await.unwrap();
+
+        let wallet = node.wallet();
+        assert_eq!(txids.len(), 1);
+        assert_eq!(
+            wallet.sent[0].1.outputs,
+            vec![("bcrt1qstore".to_owned(), plan.value())]
+        );
+
+        // Only the unconfirmed deposit and the unrelated coins are left
+        assert_eq!(wallet.utxos.len(), 2);
+        assert!(wallet
+            .utxos
+            .iter()
+            .all(|utxo| utxo.confirmations == 0 || utxo.address == "bcrt1qunrelated"));
+    }
+
+    #[actix_rt::test]
+    async fn test_partial_sweep_keeps_broadcast_txids() {
+        let node = MockBitcoind::start();
+        let backend = node.backend();
+        let (a, b) = (
+            backend.new_deposit_address("onionfans:lol").await.unwrap(),
+            backend
+                .new_deposit_address("onionfans:other")
+                .await
+                .unwrap(),
+        );
+        node.wallet().deposit(&a, Amount::from_sat(50_000), 6);
+        node.wallet().deposit(&b, Amount::from_sat(50_000), 6);
+        let credited = ids(&backend.deposits(&[&a, &b]).await.unwrap());
+        let mut plan = backend
+            .plan_sweep(&[&a], &credited, "bcrt1qstore")
+            .await
+            .unwrap();
+        plan.txs.extend(
+            backend
+                .plan_sweep(&[&b], &credited, "bcrt1qstore")
+                .await
+                .unwrap()
+                .txs,
+        );
+
+        // The second transaction's input is spent elsewhere before the sweep gets to it
+        node.wallet().utxos.retain(|utxo| utxo.address != b);
+        let e = backend.execute_sweep(&plan).await.unwrap_err();
+        assert_eq!(e.broadcast.len(), 1);
+        assert_eq!(e.broadcast[0], node.wallet().sent[0].0);
+        assert!(matches!(e.error, PaymentError::Failed(_)));
+    }
+
+    #[test]
+    fn test_payment_uri() {
+        assert_eq!(
+            BackendKind::Bitcoind.payment_uri("bc1qa", Amount::from_sat(20_000)),
+            "bitcoin:bc1qa?amount=0.00020000"
+        );
+        assert_eq!(
+            BackendKind::Monero.payment_uri("8abc", Amount::from_sat(50_000_000_001)),
+            "monero:8abc?tx_amount=0.050000000001"
+        );
+    }
+
+    #[test]
+    fn test_rpc_error_mapping() {
+        let warmup = RpcError::Rpc {
+            code: RPC_IN_WARMUP,
+            message: "Loading block index...".to_owned(),
+        };
+        assert_eq!(
+            PaymentError::from(warmup).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            PaymentError::from(RpcError::Auth("no cookie".to_owned())).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            PaymentError::from(RpcError::Rpc {
+                code: -25,
+                message: "bad-txns-inputs-missingorspent".to_owned()
+            })
+            .status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+}
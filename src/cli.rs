@@ -0,0 +1,160 @@
+use super::{
+    backend,
+    config::Config,
+    payout::{ManualRun, Scheduler},
+    store,
+};
+
+use tokio::{runtime::Runtime, task};
+
+use std::{ffi::OsString, path::PathBuf};
+
+pub const USAGE: &'static str = "usage:
+    onionfans [CONFIG]                               serve the store
+    onionfans sweep-preview [CONFIG]                 show the sweep that would happen now
+    onionfans sweep-execute CONFIRMATION [CONFIG]    execute a previewed sweep
+    onionfans migrate [--dry-run] [CONFIG]           bring the database up to date";
+
+/// An admin command run from the command line instead of serving the store.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Shows the sweep that would happen right now, without broadcasting anything
+    SweepPreview,
+
+    /// Executes the previewed sweep with the given confirmation code
+    SweepExecute(String),
+
+    /// Migrates the database to this version of the store, or only reports what that would
+    /// change
+    Migrate { dry_run: bool },
+}
+
+/// Splits the arguments (excluding the program name) into the command to run, if any, and the
+/// config file path, if any.
+pub fn parse_args<I>(args: I) -> Result<(Option<Command>, Option<PathBuf>), String>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter();
+
+    let (cmd, path) = match args.next() {
+        None => (None, None),
+        Some(arg) => match arg.to_str() {
+            Some("sweep-preview") => (Some(Command::SweepPreview), args.next()),
+            Some("sweep-execute") => {
+                let confirmation = args
+                    .next()
+                    .and_then(|code| code.into_string().ok())
+                    .ok_or_else(|| "sweep-execute requires a confirmation code".to_owned())?;
+
+                (Some(Command::SweepExecute(confirmation)), args.next())
+            }
+            Some("migrate") => {
+                let mut path = args.next();
+                let dry_run = path.as_ref().and_then(|arg| arg.to_str()) == Some("--dry-run");
+                if dry_run {
+                    path = args.next();
+                }
+
+                (Some(Command::Migrate { dry_run }), path)
+            }
+            Some("-h") | Some("--help") => return Err(USAGE.to_owned()),
+            _ => (None, Some(arg)),
+        },
+    };
+
+    if args.next().is_some() {
+        return Err(USAGE.to_owned());
+    }
+
+    Ok((cmd, path.map(PathBuf::from)))
+}
+
+/// Runs the command to completion. The store must not be running, since sled only allows one
+/// process to open the database at a time; use the /admin/sweep endpoints against a running
+/// store instead. The database is migrated first, as when the store starts.
+pub fn run(cmd: Command, cfg: &Config) -> Result<(), String> {
+    let db = sled::open(&cfg.db_path).map_err(|e| {
+        format!(
+            "could not open {} (is the store running?): {}",
+            cfg.db_path.display(),
+            e
+        )
+    })?;
+
+    let report = store::migrate(&db, cmd == Command::Migrate { dry_run: true })?;
+    if let Command::Migrate { .. } = cmd {
+        println!("{}", report);
+
+        return Ok(());
+    } else if !report.is_empty() {
+        println!("{}\n", report);
+    }
+
+    let backend = backend::from_config(cfg)?;
+    let scheduler = Scheduler::new(db, &*backend, cfg);
+    let rt = Runtime::new().map_err(|e| e.to_string())?;
+
+    task::LocalSet::new().block_on(&rt, async move {
+        match cmd {
+            Command::SweepPreview => println!("{}", scheduler.preview().await?),
+            Command::Migrate { .. } => unreachable!("migrations are run above"),
+            Command::SweepExecute(confirmation) => match scheduler.execute(&confirmation).await? {
+                ManualRun::Ran(run) => println!("{:?}", run.outcome),
+                ManualRun::Busy => return Err("a sweep is already running".to_owned()),
+                ManualRun::Changed(preview) => {
+                    return Err(format!(
+                    "the sweep has changed since it was previewed, so nothing was broadcast\n\n{}",
+                    preview
+                ))
+                }
+            },
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(args(&[])), Ok((None, None)));
+        assert_eq!(
+            parse_args(args(&["store.toml"])),
+            Ok((None, Some(PathBuf::from("store.toml"))))
+        );
+        assert_eq!(
+            parse_args(args(&["sweep-preview"])),
+            Ok((Some(Command::SweepPreview), None))
+        );
+        assert_eq!(
+            parse_args(args(&["sweep-execute", "00ff", "store.toml"])),
+            Ok((
+                Some(Command::SweepExecute("00ff".to_owned())),
+                Some(PathBuf::from("store.toml"))
+            ))
+        );
+
+        assert_eq!(
+            parse_args(args(&["migrate", "store.toml"])),
+            Ok((
+                Some(Command::Migrate { dry_run: false }),
+                Some(PathBuf::from("store.toml"))
+            ))
+        );
+        assert_eq!(
+            parse_args(args(&["migrate", "--dry-run"])),
+            Ok((Some(Command::Migrate { dry_run: true }), None))
+        );
+
+        assert!(parse_args(args(&["sweep-execute"])).is_err());
+        assert!(parse_args(args(&["store.toml", "extra"])).is_err());
+    }
+}
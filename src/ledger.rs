@@ -0,0 +1,431 @@
+use super::{
+    amount::Amount,
+    backend::{Deposit, PaymentBackend},
+    config::Config,
+    store::{self, Record, TxError, TxTable},
+    user::User,
+};
+
+use chrono::{DateTime, Utc};
+use sled::{Db, Transactional};
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, SystemTime},
+};
+
+/// The length of time purchased by a deposit of the configured monthly price.
+pub const SUBSCRIPTION_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A user's subscription: every deposit credited to them, and the date their access lapses.
+#[derive(Serialize, Deserialize)]
+pub struct Subscription {
+    pub username: String,
+
+    /// Outpoints (txid:vout) that have already been credited, so they are never counted twice
+    pub credited: HashSet<String>,
+
+    /// Credit not yet converted into a full subscription period
+    pub credit: Amount,
+    pub paid_until: SystemTime,
+}
+
+impl Subscription {
+    /// Creates an empty subscription that has never been paid for.
+    pub fn new(username: String) -> Self {
+        Self {
+            username,
+            credited: HashSet::new(),
+            credit: Amount::ZERO,
+            paid_until: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Loads the user's subscription, or an empty one if they have never deposited.
+    pub fn load(db: &Db, username: &str) -> Result<Self, String> {
+        store::ledger(db)?
+            .get(username)
+            .map(|sub| sub.unwrap_or_else(|| Self::new(username.to_owned())))
+    }
+
+    /// Loads the user's subscription within a transaction over the ledger, or an empty one if
+    /// they have never deposited.
+    pub fn load_within(ledger: &TxTable<Self>, username: &str) -> Result<Self, TxError> {
+        ledger
+            .get(username)
+            .map(|sub| sub.unwrap_or_else(|| Self::new(username.to_owned())))
+    }
+
+    /// Credits a confirmed deposit to the user, extending their subscription by as many
+    /// whole periods as their credit can buy at the given price. Returns false if the deposit
+    /// was already credited.
+    pub fn credit_deposit(
+        &mut self,
+        outpoint: String,
+        amount: Amount,
+        price: Amount,
+        now: SystemTime,
+    ) -> bool {
+        if !self.credited.insert(outpoint) {
+            return false;
+        }
+
+        self.credit += amount;
+
+        while price > Amount::ZERO && self.credit >= price {
+            // A lapsed subscription starts again from today, rather than back-filling the gap
+            self.paid_until = self.paid_until.max(now) + SUBSCRIPTION_PERIOD;
+            self.credit = self.credit.saturating_sub(price);
+        }
+
+        true
+    }
+
+    /// Determines whether or not the user's subscription covers the given moment.
+    pub fn is_active_at(&self, now: SystemTime) -> bool {
+        self.paid_until >= now
+    }
+
+    /// Determines whether or not the user's subscription is currently paid for.
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(SystemTime::now())
+    }
+
+    /// Formats the paid-through date for display, or "never" if nothing has been paid.
+    pub fn paid_until_str(&self) -> String {
+        if self.paid_until == SystemTime::UNIX_EPOCH {
+            return "never".to_owned();
+        }
+
+        DateTime::<Utc>::from(self.paid_until)
+            .format("%Y-%m-%d %H:%M UTC")
+            .to_string()
+    }
+}
+
+impl Record for Subscription {
+    const VERSION: u32 = 1;
+}
+
+impl Record for Vec<Deposit> {
+    const VERSION: u32 = 1;
+}
+
+/// Gets the user's deposits that were still waiting for confirmations as of the last sync.
+pub fn pending_deposits(db: &Db, username: &str) -> Result<Vec<Deposit>, String> {
+    store::pending_deposits(db)?
+        .get(username)
+        .map(Option::unwrap_or_default)
+}
+
+/// Credits every outstanding deposit to its owner, and records the deposits still waiting for
+/// confirmations, asking the payment backend for every user's deposits at once. Handlers read
+/// the results from the database rather than asking the backend themselves. Must run before
+/// deposits are swept, since swept outputs are no longer visible to the users that paid them.
+pub async fn sync_all(db: &Db, adapter: &dyn PaymentBackend, cfg: &Config) -> Result<(), String> {
+    let owners: HashMap<String, String> = User::deposit_addresses(db)?.into_iter().collect();
+    let deposits = adapter
+        .deposits(&owners.keys().map(String::as_str).collect::<Vec<_>>())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Every owner is visited, so that swept deposits stop showing as pending
+    let mut by_owner: BTreeMap<&str, Vec<Deposit>> = owners
+        .values()
+        .map(|username| (username.as_str(), Vec::new()))
+        .collect();
+    for deposit in deposits {
+        let owner = deposit.address.as_ref().and_then(|addr| owners.get(addr));
+
+        if let Some(owned) = owner.and_then(|username| by_owner.get_mut(username.as_str())) {
+            owned.push(deposit);
+        }
+    }
+
+    // Each user's credit, pending deposits and used addresses are saved together, so that
+    // concurrent syncs and Lightning payments can't interleave their writes
+    let (ledger, pending_deposits) = (store::ledger(db)?, store::pending_deposits(db)?);
+    let used_addresses = store::used_addresses(db)?;
+    let now = SystemTime::now();
+    for (username, deposits) in by_owner {
+        store::committed(
+            (
+                ledger.tree(),
+                pending_deposits.tree(),
+                used_addresses.tree(),
+            )
+                .transaction(|(tx_ledger, tx_pending, tx_used)| {
+                    let tx_ledger = ledger.within(tx_ledger);
+                    let mut sub = Subscription::load_within(&tx_ledger, username)?;
+                    let mut pending = Vec::new();
+                    let mut credited = false;
+
+                    // Unconfirmed deposits are only credited while the user's unconfirmed credit
+                    // as a whole stays below zero_conf_limit, so that splitting a payment into
+                    // small deposits doesn't get around it
+                    let mut unconfirmed_credit: Amount = deposits
+                        .iter()
+                        .filter(|deposit| {
+                            !deposit.is_confirmed(cfg) && sub.credited.contains(&deposit.id())
+                        })
+                        .map(|deposit| deposit.amount)
+                        .sum();
+
+                    for deposit in &deposits {
+                        // Addresses are rotated as soon as they are paid, whether or not the
+                        // payment confirms
+                        if let Some(addr) = &deposit.address {
+                            User::mark_used(&used_addresses.within(tx_used), addr)?;
+                        }
+
+                        let creditable =
+                            if deposit.is_confirmed(cfg) || sub.credited.contains(&deposit.id()) {
+                                true
+                            } else if unconfirmed_credit + deposit.amount < cfg.zero_conf_limit() {
+                                unconfirmed_credit += deposit.amount;
+
+                                true
+                            } else {
+                                false
+                            };
+
+                        if creditable {
+                            credited |= sub.credit_deposit(
+                                deposit.id(),
+                                deposit.amount,
+                                cfg.monthly_price(),
+                                now,
+                            );
+                        } else {
+                            pending.push(deposit.clone());
+                        }
+                    }
+
+                    if credited {
+                        tx_ledger.insert(username, &sub)?;
+                    }
+
+                    let tx_pending = pending_deposits.within(tx_pending);
+                    if pending.is_empty() {
+                        tx_pending.remove(username)
+                    } else {
+                        tx_pending.insert(username, &pending)
+                    }
+                }),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{backend::Bitcoind, mock_rpc::MockBitcoind};
+
+    const MONTHLY_BTC: Amount = Amount::from_sat(20_000);
+    const HALF_MONTH_BTC: Amount = Amount::from_sat(10_000);
+
+    #[test]
+    fn test_credit_deposit() {
+        let now = SystemTime::now();
+        let mut sub = Subscription::new("lol".to_owned());
+        assert!(!sub.is_active_at(now));
+
+        // Not enough for a whole period
+        assert!(sub.credit_deposit("a:0".to_owned(), HALF_MONTH_BTC, MONTHLY_BTC, now));
+        assert!(!sub.is_active_at(now));
+
+        // The same outpoint is never credited twice
+        assert!(!sub.credit_deposit("a:0".to_owned(), HALF_MONTH_BTC, MONTHLY_BTC, now));
+        assert!(!sub.is_active_at(now));
+
+        assert!(sub.credit_deposit(
+            "b:1".to_owned(),
+            MONTHLY_BTC + MONTHLY_BTC,
+            MONTHLY_BTC,
+            now
+        ));
+        assert!(sub.is_active_at(now));
+        assert_eq!(sub.paid_until, now + SUBSCRIPTION_PERIOD * 2);
+        assert_eq!(sub.credit, HALF_MONTH_BTC);
+
+        // Access lapses once the paid-through date has passed
+        assert!(!sub.is_active_at(now + SUBSCRIPTION_PERIOD * 3));
+    }
+
+    #[test]
+    fn test_commit_load() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut sub = Subscription::load(&db, "lol").unwrap();
+        assert_eq!(sub.paid_until_str(), "never");
+
+        sub.credit_deposit(
+            "a:0".to_owned(),
+            MONTHLY_BTC,
+            MONTHLY_BTC,
+            SystemTime::now(),
+        );
+        store::ledger(&db).unwrap().insert("lol", &sub).unwrap();
+
+        assert!(Subscription::load(&db, "lol").unwrap().is_active());
+    }
+
+    /// Registers a user owning the given deposit addresses.
+    fn register(db: &Db, username: &str, addrs: &[&str]) {
+        let mut u = User::new(username.to_owned(), "hunter2".to_owned()).unwrap();
+        u.btc_addresses = addrs.iter().map(|addr| addr.to_string()).collect();
+        assert!(u.register(db).unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_sync_all() {
+        let node = MockBitcoind::start();
+        let adapter = node.backend();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config::default();
+        register(&db, "lol", &["bcrt1qa", "bcrt1qb"]);
+        register(&db, "other", &["bcrt1qc"]);
+        {
+            let mut wallet = node.wallet();
+            wallet.deposit("bcrt1qa", Amount::from_sat(10_000), 1);
+            wallet.deposit("bcrt1qc", Amount::from_sat(10_000), 1);
+
+            // Not the store's
+            wallet.deposit("bcrt1qunrelated", Amount::from_sat(50_000), 1);
+        }
+
+        sync_all(&db, &adapter, &cfg).await.unwrap();
+        assert!(!Subscription::load(&db, "lol").unwrap().is_active());
+
+        // Paid addresses aren't handed out again
+        let u = User::load(&db, "lol").unwrap().unwrap();
+        assert_eq!(u.unused_addresses(&db).unwrap(), vec!["bcrt1qb"]);
+        assert_eq!(
+            Subscription::load(&db, "other").unwrap().credit,
+            Amount::from_sat(10_000)
+        );
+
+        // Deposits add up until they cover a whole period, however many addresses they are to
+        node.wallet()
+            .deposit("bcrt1qb", Amount::from_sat(10_000), 1);
+        sync_all(&db, &adapter, &cfg).await.unwrap();
+        assert!(Subscription::load(&db, "lol").unwrap().is_active());
+
+        // Every user's deposits are fetched in a single call
+        let calls = node.wallet().calls.clone();
+        assert_eq!(
+            calls.iter().filter(|call| *call == "listunspent").count(),
+            2
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_credit_across_addresses() {
+        let node = MockBitcoind::start();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        register(&db, "lol", &["bcrt1qa", "bcrt1qb"]);
+        {
+            let mut wallet = node.wallet();
+            wallet.deposit("bcrt1qa", Amount::from_sat(6_927), 1);
+            wallet.deposit("bcrt1qb", Amount::from_sat(10_000), 3);
+
+            // Unconfirmed, and someone else's
+            wallet.deposit("bcrt1qa", Amount::from_sat(50_000), 0);
+            wallet.deposit("bcrt1qc", Amount::from_sat(50_000), 6);
+        }
+
+        sync_all(&db, &node.backend(), &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            Subscription::load(&db, "lol").unwrap().credit,
+            Amount::from_sat(16_927)
+        );
+        assert_eq!(pending_deposits(&db, "lol").unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_confirmation_policy() {
+        let node = MockBitcoind::start();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            min_confirmations: 3,
+            zero_conf_limit: Amount::from_sat(5_000),
+            ..Config::default()
+        };
+        let adapter = Bitcoind::new(node.connect(), &cfg);
+        register(&db, "lol", &["bcrt1qa"]);
+        {
+            let mut wallet = node.wallet();
+            wallet.deposit("bcrt1qa", Amount::from_sat(15_000), 2);
+
+            // Small enough to be credited unconfirmed
+            wallet.deposit("bcrt1qa", Amount::from_sat(4_000), 0);
+            wallet.deposit("bcrt1qa", Amount::from_sat(6_000), 0);
+        }
+
+        sync_all(&db, &adapter, &cfg).await.unwrap();
+        let pending = pending_deposits(&db, "lol").unwrap();
+        assert_eq!(
+            Subscription::load(&db, "lol").unwrap().credit,
+            Amount::from_sat(4_000)
+        );
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].amount, Amount::from_sat(15_000));
+        assert_eq!(pending[0].confirmations, 2);
+
+        // Pending deposits are credited once they are deep enough
+        for utxo in &mut node.wallet().utxos {
+            utxo.confirmations += 3;
+        }
+        sync_all(&db, &adapter, &cfg).await.unwrap();
+        let sub = Subscription::load(&db, "lol").unwrap();
+        assert!(pending_deposits(&db, "lol").unwrap().is_empty());
+        assert!(sub.is_active());
+        assert_eq!(sub.credit, Amount::from_sat(5_000));
+    }
+
+    #[actix_rt::test]
+    async fn test_split_zero_conf_deposits() {
+        let node = MockBitcoind::start();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            min_confirmations: 3,
+            zero_conf_limit: Amount::from_sat(5_000),
+            ..Config::default()
+        };
+        let adapter = Bitcoind::new(node.connect(), &cfg);
+        register(&db, "lol", &["bcrt1qa", "bcrt1qb"]);
+        {
+            // A payment split into outputs that are each under the limit
+            let mut wallet = node.wallet();
+            for addr in &["bcrt1qa", "bcrt1qb", "bcrt1qa", "bcrt1qb"] {
+                wallet.deposit(addr, Amount::from_sat(2_000), 0);
+            }
+        }
+
+        // Only as much as the limit allows is credited before confirming, however many times
+        // the deposits are synced
+        for _ in 0..2 {
+            sync_all(&db, &adapter, &cfg).await.unwrap();
+            assert_eq!(
+                Subscription::load(&db, "lol").unwrap().credit,
+                Amount::from_sat(4_000)
+            );
+            assert_eq!(pending_deposits(&db, "lol").unwrap().len(), 2);
+        }
+
+        // The rest is credited once it confirms
+        for utxo in &mut node.wallet().utxos {
+            utxo.confirmations = 3;
+        }
+        sync_all(&db, &adapter, &cfg).await.unwrap();
+        assert_eq!(
+            Subscription::load(&db, "lol").unwrap().credit,
+            Amount::from_sat(8_000)
+        );
+        assert!(pending_deposits(&db, "lol").unwrap().is_empty());
+    }
+}
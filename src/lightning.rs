@@ -0,0 +1,363 @@
+use super::{
+    amount::Amount,
+    backend::PaymentError,
+    config::Config,
+    ledger::Subscription,
+    store::{self, Record},
+};
+
+use reqwest::{Certificate, Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use sled::{Db, Transactional};
+
+use std::{
+    collections::HashSet,
+    fs,
+    time::{Duration, SystemTime},
+};
+
+/// A BOLT11 invoice issued to a user for one subscription period, kept until it settles or
+/// expires.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Invoice {
+    /// The payment hash, in hex
+    pub r_hash: String,
+    pub payment_request: String,
+    pub amount: Amount,
+    pub expires_at: SystemTime,
+}
+
+impl Invoice {
+    /// Identifies the invoice in the ledger, so that it is never credited twice.
+    pub fn id(&self) -> String {
+        format!("ln:{}", self.r_hash)
+    }
+
+    /// Gets the user's unsettled invoices, oldest first.
+    pub fn pending(db: &Db, username: &str) -> Result<Vec<Self>, String> {
+        store::invoices(db)?
+            .get(username)
+            .map(Option::unwrap_or_default)
+    }
+}
+
+impl Record for Vec<Invoice> {
+    const VERSION: u32 = 1;
+}
+
+#[derive(Deserialize)]
+struct AddInvoiceResponse {
+    /// Base64, like every bytes field in LND's REST interface
+    r_hash: String,
+    payment_request: String,
+}
+
+#[derive(Deserialize)]
+struct LookupInvoiceResponse {
+    /// "OPEN", "SETTLED", "CANCELED" or "ACCEPTED"
+    state: String,
+
+    /// A decimal string, like every 64-bit integer in LND's REST interface
+    #[serde(default)]
+    amt_paid_sat: String,
+}
+
+#[derive(Deserialize)]
+struct LndErrorResponse {
+    message: String,
+}
+
+/// Issues subscription invoices through LND's REST interface, as an alternative to on-chain
+/// deposits for stores taking bitcoin.
+pub struct Lnd {
+    url: String,
+
+    /// Sent hex-encoded with every request. Only the invoice macaroon's permissions are needed
+    macaroon: String,
+    client: Client,
+
+    /// How long issued invoices stay payable
+    expiry: Duration,
+}
+
+impl Lnd {
+    /// Connects to LND's REST interface at the given URL, e.g. https://127.0.0.1:8080,
+    /// authenticating with the macaroon. LND's own self-signed TLS certificate may be given to
+    /// trust it.
+    pub fn new(
+        url: &str,
+        macaroon: &[u8],
+        tls_cert: Option<&[u8]>,
+        expiry_secs: u64,
+    ) -> Result<Self, String> {
+        let mut client = Client::builder();
+        if let Some(pem) = tls_cert {
+            client =
+                client.add_root_certificate(Certificate::from_pem(pem).map_err(|e| e.to_string())?);
+        }
+
+        Ok(Self {
+            url: url.trim_end_matches('/').to_owned(),
+            macaroon: macaroon.iter().map(|b| format!("{:02x}", b)).collect(),
+            client: client.build().map_err(|e| e.to_string())?,
+            expiry: Duration::from_secs(expiry_secs),
+        })
+    }
+
+    /// Connects to the LND node configured for the store, if any.
+    pub fn from_config(cfg: &Config) -> Result<Option<Self>, String> {
+        let url = match &cfg.lnd_rest_url {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let read = |path: &std::path::Path| {
+            fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))
+        };
+
+        let macaroon = match &cfg.lnd_macaroon_file {
+            Some(path) => read(path)?,
+            None => return Err("lnd_rest_url requires lnd_macaroon_file".to_owned()),
+        };
+        let tls_cert = cfg.lnd_tls_cert_file.as_deref().map(read).transpose()?;
+
+        Self::new(url, &macaroon, tls_cert.as_deref(), cfg.invoice_expiry_secs).map(Some)
+    }
+
+    /// Sends the request with the macaroon, decoding the response into T.
+    async fn send<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T, PaymentError> {
+        let resp = req
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+            .send()
+            .await
+            .map_err(|e| PaymentError::Unavailable(format!("could not reach LND: {}", e)))?;
+        let status = resp.status();
+        let body = resp
+            .bytes()
+            .await
+            .map_err(|e| PaymentError::Unavailable(format!("could not reach LND: {}", e)))?;
+
+        if !status.is_success() {
+            let message = serde_json::from_slice::<LndErrorResponse>(&body)
+                .map(|e| e.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+
+            // LND reports a bad macaroon like any other error
+            return Err(if message.contains("verification failed") {
+                PaymentError::Misconfigured(format!("LND rejected the macaroon: {}", message))
+            } else {
+                PaymentError::Failed(format!("LND returned HTTP {}: {}", status, message))
+            });
+        }
+
+        serde_json::from_slice(&body)
+            .map_err(|e| PaymentError::Failed(format!("invalid LND response: {}", e)))
+    }
+
+    /// Issues an invoice to the user for one subscription period, keeping it until it settles.
+    pub async fn new_invoice(
+        &self,
+        db: &Db,
+        username: &str,
+        cfg: &Config,
+    ) -> Result<Invoice, String> {
+        let amount = cfg.monthly_price();
+        let added: AddInvoiceResponse = self
+            .send(
+                self.client
+                    .post(format!("{}/v1/invoices", self.url))
+                    .json(&json!({
+                        "value": amount.as_sat().to_string(),
+                        "memo": format!("{}: 30 days for {}", cfg.store_label, username),
+                        "expiry": self.expiry.as_secs().to_string(),
+                    })),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let invoice = Invoice {
+            r_hash: base64::decode(&added.r_hash)
+                .map_err(|e| format!("invalid LND payment hash: {}", e))?
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            payment_request: added.payment_request,
+            amount,
+            expires_at: SystemTime::now() + self.expiry,
+        };
+
+        let invoices = store::invoices(db)?;
+        store::committed(invoices.tree().transaction(|tx_invoices| {
+            let tx_invoices = invoices.within(tx_invoices);
+            let mut pending = tx_invoices.get(username)?.unwrap_or_default();
+            pending.push(invoice.clone());
+
+            tx_invoices.insert(username, &pending)
+        }))?;
+
+        Ok(invoice)
+    }
+
+    /// Credits the user's settled invoices to their subscription, and forgets any that can no
+    /// longer be paid. Returns the newest invoice that can still be paid, if any.
+    pub async fn sync_invoices(
+        &self,
+        db: &Db,
+        username: &str,
+        cfg: &Config,
+    ) -> Result<Option<Invoice>, String> {
+        let pending = Invoice::pending(db, username)?;
+        if pending.is_empty() {
+            return Ok(None);
+        }
+
+        let now = SystemTime::now();
+        let mut settled = Vec::new();
+        let mut resolved = HashSet::new();
+        let mut unsettled = Vec::new();
+
+        for invoice in pending {
+            let lookup: LookupInvoiceResponse = self
+                .send(
+                    self.client
+                        .get(format!("{}/v1/invoice/{}", self.url, invoice.r_hash)),
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+
+            match lookup.state.as_str() {
+                "SETTLED" => {
+                    let paid = lookup
+                        .amt_paid_sat
+                        .parse()
+                        .map(Amount::from_sat)
+                        .map_err(|e| format!("invalid LND amount paid: {}", e))?;
+                    resolved.insert(invoice.r_hash.clone());
+                    settled.push((invoice.id(), paid));
+                }
+                "CANCELED" => {
+                    resolved.insert(invoice.r_hash);
+                }
+
+                // LND cancels expired invoices itself, but not necessarily right away
+                "OPEN" if invoice.expires_at <= now => {
+                    resolved.insert(invoice.r_hash);
+                }
+                _ => unsettled.push(invoice),
+            }
+        }
+
+        // Credit is saved as settled invoices are forgotten, so none can be lost or credited
+        // twice. Invoices issued since they were looked up are kept
+        let (ledger, invoices) = (store::ledger(db)?, store::invoices(db)?);
+        store::committed((ledger.tree(), invoices.tree()).transaction(
+            |(tx_ledger, tx_invoices)| {
+                let tx_ledger = ledger.within(tx_ledger);
+                let mut sub = Subscription::load_within(&tx_ledger, username)?;
+                for (id, paid) in &settled {
+                    sub.credit_deposit(id.clone(), *paid, cfg.monthly_price(), now);
+                }
+                tx_ledger.insert(username, &sub)?;
+
+                let tx_invoices = invoices.within(tx_invoices);
+                let pending: Vec<Invoice> = tx_invoices
+                    .get(username)?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|invoice| !resolved.contains(&invoice.r_hash))
+                    .collect();
+                if pending.is_empty() {
+                    tx_invoices.remove(username)
+                } else {
+                    tx_invoices.insert(username, &pending)
+                }
+            },
+        ))?;
+
+        Ok(unsettled
+            .into_iter()
+            .rev()
+            .find(|invoice| invoice.expires_at > now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock_rpc::MockLnd;
+
+    #[actix_rt::test]
+    async fn test_invoice_settles_once() {
+        let node = MockLnd::start();
+        let lnd = node.backend();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config::default();
+
+        let invoice = lnd.new_invoice(&db, "lol", &cfg).await.unwrap();
+        assert_eq!(invoice.amount, cfg.monthly_btc);
+        assert_eq!(node.node().invoices[0].value, cfg.monthly_btc.as_sat());
+        assert_eq!(node.node().invoices[0].r_hash, invoice.r_hash);
+        assert_eq!(node.node().invoices[0].memo, "onionfans: 30 days for lol");
+        assert_eq!(node.node().invoices[0].expiry, 3600);
+
+        // Still waiting to be paid
+        assert_eq!(
+            lnd.sync_invoices(&db, "lol", &cfg).await.unwrap(),
+            Some(invoice.clone())
+        );
+        assert!(!Subscription::load(&db, "lol").unwrap().is_active());
+
+        node.node().settle(&invoice.r_hash);
+        assert_eq!(lnd.sync_invoices(&db, "lol", &cfg).await.unwrap(), None);
+        assert!(Invoice::pending(&db, "lol").unwrap().is_empty());
+
+        let sub = Subscription::load(&db, "lol").unwrap();
+        assert!(sub.is_active());
+        assert!(sub.credited.contains(&invoice.id()));
+
+        // Settled invoices are forgotten, so they can't be looked up and credited again
+        let calls = node.node().calls.len();
+        assert_eq!(lnd.sync_invoices(&db, "lol", &cfg).await.unwrap(), None);
+        assert_eq!(node.node().calls.len(), calls);
+    }
+
+    #[actix_rt::test]
+    async fn test_canceled_invoices_are_forgotten() {
+        let node = MockLnd::start();
+        let lnd = node.backend();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config::default();
+
+        let first = lnd.new_invoice(&db, "lol", &cfg).await.unwrap();
+        let second = lnd.new_invoice(&db, "lol", &cfg).await.unwrap();
+
+        // The newest payable invoice is the one shown
+        assert_eq!(
+            lnd.sync_invoices(&db, "lol", &cfg).await.unwrap(),
+            Some(second.clone())
+        );
+
+        node.node().cancel(&second.r_hash);
+        assert_eq!(
+            lnd.sync_invoices(&db, "lol", &cfg).await.unwrap(),
+            Some(first.clone())
+        );
+        assert_eq!(Invoice::pending(&db, "lol").unwrap(), vec![first]);
+        assert!(!Subscription::load(&db, "lol").unwrap().is_active());
+    }
+
+    #[actix_rt::test]
+    async fn test_bad_macaroon() {
+        let node = MockLnd::start();
+        let lnd = Lnd::new(&node.backend().url, b"wrong", None, 3600).unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let err = lnd
+            .new_invoice(&db, "lol", &Config::default())
+            .await
+            .unwrap_err();
+        assert!(err.contains("macaroon"));
+        assert!(Invoice::pending(&db, "lol").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,429 @@
+use super::{admin, auth::PostHist, config::Config, store};
+
+use actix_web::{error, get, http, web, HttpRequest, HttpResponse, Result};
+use sled::{Db, Transactional};
+use tokio::time;
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Counts the links the reaper has expired since the store started.
+#[derive(Clone, Default)]
+pub struct LinkMetrics(Arc<AtomicU64>);
+
+/// Why a link couldn't be followed.
+#[derive(Debug, PartialEq)]
+pub enum FollowError {
+    /// No such link was issued, or it has been reaped
+    Unknown,
+
+    /// The link was issued to another session
+    OtherSession,
+
+    /// The link outlived its lifetime
+    Expired,
+
+    /// The content was already sent in full
+    Used,
+
+    Storage(String),
+}
+
+/// Builds the key indexing a link issued at the given time. Issue times before the epoch sort
+/// first.
+fn expiry_key(created_at: SystemTime, id: &str) -> Vec<u8> {
+    let created_ms = created_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0);
+
+    let mut key = created_ms.to_be_bytes().to_vec();
+    key.extend_from_slice(id.as_bytes());
+
+    key
+}
+
+/// Records a link to the content at the path for the session, along with its place in the
+/// expiry index.
+pub fn issue(db: &Db, id: &str, path: &str, session: &str) -> Result<(), String> {
+    let hist = PostHist {
+        path: path.to_owned(),
+        created_at: SystemTime::now(),
+        session: session.to_owned(),
+        delivered: false,
+    };
+    let (links, expiry) = (store::links(db)?, store::link_expiry(db)?);
+
+    store::committed(
+        (links.tree(), expiry.tree()).transaction(|(tx_links, tx_expiry)| {
+            links.within(tx_links).insert(id, &hist)?;
+            expiry
+                .within(tx_expiry)
+                .insert(expiry_key(hist.created_at, id), &())?;
+
+            Ok(())
+        }),
+    )
+}
+
+/// Forgets the link, along with its place in the expiry index.
+pub fn remove(db: &Db, id: &str, hist: &PostHist) -> Result<(), String> {
+    let (links, expiry) = (store::links(db)?, store::link_expiry(db)?);
+
+    store::committed(
+        (links.tree(), expiry.tree()).transaction(|(tx_links, tx_expiry)| {
+            links.within(tx_links).remove(id)?;
+            expiry
+                .within(tx_expiry)
+                .remove(expiry_key(hist.created_at, id))?;
+
+            Ok(())
+        }),
+    )
+}
+
+/// Follows the link on behalf of the session. Links whose content was already sent in full can
+/// only be followed for ranges of it, until they expire. Expired links are forgotten.
+pub fn follow(
+    db: &Db,
+    id: &str,
+    session: &str,
+    whole: bool,
+    lifetime: Duration,
+    now: SystemTime,
+) -> Result<PostHist, FollowError> {
+    let (links, expiry) = (
+        store::links(db).map_err(FollowError::Storage)?,
+        store::link_expiry(db).map_err(FollowError::Storage)?,
+    );
+
+    // Refusals are returned inside the transaction's result, so that forgetting an expired link
+    // still commits
+    store::committed(
+        (links.tree(), expiry.tree()).transaction(|(tx_links, tx_expiry)| {
+            let tx_links = links.within(tx_links);
+            let hist = match tx_links.get(id)? {
+                Some(hist) => hist,
+                None => return Ok(Err(FollowError::Unknown)),
+            };
+
+            if hist.session != session {
+                return Ok(Err(FollowError::OtherSession));
+            }
+
+            // The reaper forgets expired links soon enough, but not necessarily before they are
+            // followed
+            if hist.created_at + lifetime < now {
+                tx_links.remove(id)?;
+                expiry
+                    .within(tx_expiry)
+                    .remove(expiry_key(hist.created_at, id))?;
+
+                return Ok(Err(FollowError::Expired));
+            }
+
+            if whole && hist.delivered {
+                return Ok(Err(FollowError::Used));
+            }
+
+            Ok(Ok(hist))
+        }),
+    )
+    .map_err(FollowError::Storage)?
+}
+
+/// Records that the link's content is being sent in full, using the link up. Only one of
+/// several requests racing to send it wins; the others are refused as Used.
+pub fn mark_delivered(db: &Db, id: &str) -> Result<(), FollowError> {
+    let links = store::links(db).map_err(FollowError::Storage)?;
+
+    store::committed(links.tree().transaction(|tx_links| {
+        let tx_links = links.within(tx_links);
+        let mut hist = match tx_links.get(id)? {
+            Some(hist) => hist,
+            None => return Ok(Err(FollowError::Unknown)),
+        };
+
+        if hist.delivered {
+            return Ok(Err(FollowError::Used));
+        }
+
+        hist.delivered = true;
+        tx_links.insert(id, &hist)?;
+
+        Ok(Ok(()))
+    }))
+    .map_err(FollowError::Storage)?
+}
+
+/// Forgets every link issued more than lifetime before now, walking the expiry index from the
+/// oldest link. Returns the number of links forgotten.
+pub fn reap(db: &Db, lifetime: Duration, now: SystemTime) -> Result<u64, String> {
+    let (links, expiry) = (store::links(db)?, store::link_expiry(db)?);
+    let cutoff = match now.checked_sub(lifetime) {
+        Some(cutoff) => expiry_key(cutoff, ""),
+        // Nothing can have been issued long enough ago
+        None => return Ok(0),
+    };
+    let mut reaped = 0;
+
+    for entry in expiry.range(..cutoff) {
+        let key = entry?.0;
+        let id = String::from_utf8_lossy(&key[8..]).into_owned();
+
+        store::committed(
+            (links.tree(), expiry.tree()).transaction(|(tx_links, tx_expiry)| {
+                links.within(tx_links).remove(&id)?;
+                expiry.within(tx_expiry).remove(&key)?;
+
+                Ok(())
+            }),
+        )?;
+        reaped += 1;
+    }
+
+    Ok(reaped)
+}
+
+/// Indexes every link, forgetting those that have expired. Links issued before the expiry index
+/// existed are only found this way. Returns the number of links forgotten.
+pub fn reindex(db: &Db, lifetime: Duration, now: SystemTime) -> Result<u64, String> {
+    let (links, expiry) = (store::links(db)?, store::link_expiry(db)?);
+    let mut reaped = 0;
+
+    for entry in links.iter() {
+        let (id, hist) = entry?;
+
+        if hist.created_at + lifetime < now {
+            remove(db, &id, &hist)?;
+            reaped += 1;
+        } else {
+            expiry.insert(expiry_key(hist.created_at, &id), &())?;
+        }
+    }
+
+    Ok(reaped)
+}
+
+/// Forgets links as they expire, whether or not they were ever followed, so that feed views
+/// don't grow the database without bound.
+pub struct Reaper<'a> {
+    db: Db,
+    cfg: &'a Config,
+    metrics: LinkMetrics,
+}
+
+impl<'a> Reaper<'a> {
+    pub fn new(db: Db, cfg: &'a Config, metrics: LinkMetrics) -> Self {
+        Self { db, cfg, metrics }
+    }
+
+    /// Forgets expired links every link_lifetime_secs, so that none outlives its lifetime by
+    /// more than that again. Runs forever.
+    pub async fn run(self) {
+        // Links issued before the expiry index existed are only found by a full scan
+        let mut reaped = reindex(&self.db, self.cfg.link_lifetime(), SystemTime::now());
+        loop {
+            match reaped {
+                Ok(n) => {
+                    self.metrics.0.fetch_add(n, Ordering::Relaxed);
+                }
+                Err(e) => eprintln!("link reaper error: {}", e),
+            }
+
+            time::sleep(self.cfg.link_lifetime()).await;
+            reaped = reap(&self.db, self.cfg.link_lifetime(), SystemTime::now());
+        }
+    }
+}
+
+/// Reports the number of links not yet reaped, and the number the reaper has expired since the
+/// store started, in Prometheus' text format. Scrapers authenticate with the admin password as
+/// a bearer token.
+#[get("/metrics")]
+pub async fn serve_metrics(
+    req: HttpRequest,
+    db: web::Data<Db>,
+    cfg: web::Data<Config>,
+    metrics: web::Data<LinkMetrics>,
+) -> Result<HttpResponse> {
+    let token = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    admin::check_admin_pass(&cfg, token)?;
+
+    let live = store::links(&db)
+        .map(|links| links.tree().len())
+        .map_err(|e| error::ErrorInternalServerError(e))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(format!(
+            "# HELP onionfans_live_links Feed links issued and not yet reaped.
+# TYPE onionfans_live_links gauge
+onionfans_live_links {}
+# HELP onionfans_reaped_links_total Feed links expired by the reaper.
+# TYPE onionfans_reaped_links_total counter
+onionfans_reaped_links_total {}
+",
+            live,
+            metrics.0.load(Ordering::Relaxed)
+        )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, App};
+
+    const LIFETIME: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_reap() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let now = SystemTime::now();
+        issue(&db, "a.jpg", "/content/a.jpg", "token").unwrap();
+        issue(&db, "b.jpg", "/content/b.jpg", "token").unwrap();
+        let links = store::links(&db).unwrap();
+
+        // Neither has expired yet
+        assert_eq!(reap(&db, LIFETIME, now).unwrap(), 0);
+        assert_eq!(links.tree().len(), 2);
+
+        // Followed links are forgotten along with their place in the index
+        let followed = links.get("a.jpg").unwrap().unwrap();
+        remove(&db, "a.jpg", &followed).unwrap();
+        assert_eq!(store::link_expiry(&db).unwrap().tree().len(), 1);
+
+        // Unfollowed links are forgotten once they expire
+        assert_eq!(reap(&db, LIFETIME, now + LIFETIME * 2).unwrap(), 1);
+        assert!(links.tree().is_empty());
+        assert!(store::link_expiry(&db).unwrap().tree().is_empty());
+
+        // Lifetimes reaching back before the epoch expire nothing
+        assert_eq!(reap(&db, LIFETIME, SystemTime::UNIX_EPOCH).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_follow() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let now = SystemTime::now();
+        issue(&db, "a.mov", "/content/a.mov", "token").unwrap();
+        let follow_a = |session, whole, now| follow(&db, "a.mov", session, whole, LIFETIME, now);
+
+        assert_eq!(
+            follow(&db, "b.mov", "token", true, LIFETIME, now).err(),
+            Some(FollowError::Unknown)
+        );
+        assert_eq!(
+            follow_a("other", true, now).err(),
+            Some(FollowError::OtherSession)
+        );
+
+        // Following the link doesn't use it up until the content is actually sent in full
+        assert!(!follow_a("token", false, now).unwrap().delivered);
+        assert_eq!(follow_a("token", true, now).unwrap().path, "/content/a.mov");
+        assert!(!follow_a("token", true, now).unwrap().delivered);
+
+        // Ranges can be asked for before and after the content is sent in full, but only once
+        // in full
+        assert_eq!(mark_delivered(&db, "a.mov"), Ok(()));
+        assert_eq!(mark_delivered(&db, "a.mov"), Err(FollowError::Used));
+        assert_eq!(follow_a("token", true, now).err(), Some(FollowError::Used));
+        assert!(follow_a("token", false, now).unwrap().delivered);
+        assert_eq!(mark_delivered(&db, "b.mov"), Err(FollowError::Unknown));
+
+        // Expired links are forgotten as they are followed
+        assert_eq!(
+            follow_a("token", false, now + LIFETIME * 2).err(),
+            Some(FollowError::Expired)
+        );
+        assert_eq!(
+            follow_a("token", false, now).err(),
+            Some(FollowError::Unknown)
+        );
+        assert!(store::link_expiry(&db).unwrap().tree().is_empty());
+    }
+
+    #[test]
+    fn test_reindex() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let now = SystemTime::now();
+        let links = store::links(&db).unwrap();
+
+        // Issued before links were indexed
+        for (id, age) in &[
+            ("old.jpg", LIFETIME * 2),
+            ("new.jpg", Duration::from_secs(1)),
+        ] {
+            links
+                .insert(
+                    id,
+                    &PostHist {
+                        path: format!("/content/{}", id),
+                        created_at: now - *age,
+                        session: "token".to_owned(),
+                        delivered: false,
+                    },
+                )
+                .unwrap();
+        }
+        assert_eq!(reap(&db, LIFETIME, now + LIFETIME * 2).unwrap(), 0);
+
+        assert_eq!(reindex(&db, LIFETIME, now).unwrap(), 1);
+        assert!(links.get("old.jpg").unwrap().is_none());
+        assert_eq!(reap(&db, LIFETIME, now + LIFETIME * 2).unwrap(), 1);
+        assert!(links.tree().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let metrics = LinkMetrics::default();
+        issue(&db, "a.jpg", "/content/a.jpg", "token").unwrap();
+        metrics.0.fetch_add(3, Ordering::Relaxed);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(db.clone()))
+                .app_data(web::Data::new(Config {
+                    admin_pass: "hunter2".to_owned(),
+                    ..Config::default()
+                }))
+                .app_data(web::Data::new(metrics))
+                .service(serve_metrics),
+        )
+        .await;
+        let scrape = |auth: Option<&str>| {
+            let rq = test::TestRequest::get().uri("/metrics");
+            match auth {
+                Some(auth) => rq.insert_header((http::header::AUTHORIZATION, auth)),
+                None => rq,
+            }
+            .to_request()
+        };
+
+        // Only the admin can see how busy the store is
+        for auth in &[None, Some("Bearer hunter3"), Some("Basic hunter2")] {
+            let resp = test::call_service(&app, scrape(*auth)).await;
+            assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        }
+
+        let resp = test::call_service(&app, scrape(Some("Bearer hunter2"))).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp = test::read_body(resp).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+
+        assert!(body.contains("\nonionfans_live_links 1\n"));
+        assert!(body.contains("\nonionfans_reaped_links_total 3\n"));
+    }
+}
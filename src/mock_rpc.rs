@@ -0,0 +1,868 @@
+use super::{
+    amount::Amount, backend::Bitcoind, config::Config, ingress::RpcConnection, lightning::Lnd,
+    monero::MoneroWallet,
+};
+
+use serde_json::{json, Value};
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+/// bitcoind's RPC_METHOD_NOT_FOUND code.
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+
+/// bitcoind's RPC_INVALID_ADDRESS_OR_KEY code, also used for unknown or spent inputs.
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+
+/// A coin held by the mock wallet.
+#[derive(Clone, Debug)]
+pub struct MockUtxo {
+    pub txid: String,
+    pub vout: u32,
+    pub address: String,
+    pub amount: Amount,
+    pub confirmations: u64,
+}
+
+/// A transaction created with createrawtransaction.
+#[derive(Clone, Debug)]
+pub struct MockTx {
+    pub inputs: Vec<(String, u32)>,
+    pub outputs: Vec<(String, Amount)>,
+}
+
+/// Everything the mock node knows, which tests may inspect and change at any time.
+#[derive(Default, Debug)]
+pub struct MockWallet {
+    /// Every address handed out by getnewaddress, with its label and type
+    pub addresses: HashMap<String, (String, String)>,
+    pub utxos: Vec<MockUtxo>,
+
+    /// Transactions created but not yet broadcast, by their hex
+    pub drafts: HashMap<String, MockTx>,
+
+    /// Transactions broadcast with sendrawtransaction, by their txid
+    pub sent: Vec<(String, MockTx)>,
+
+    /// The fee rate estimatesmartfee reports, in BTC/kvB, or None for too little data
+    pub fee_rate: Option<f64>,
+
+    /// When false, signrawtransactionwithwallet reports the transaction as incomplete
+    pub can_sign: bool,
+
+    /// Methods that fail with the given code and message instead of running
+    pub failures: HashMap<String, (i64, String)>,
+
+    /// The name of every method called, in order
+    pub calls: Vec<String>,
+
+    next_id: u64,
+}
+
+impl MockWallet {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+
+        self.next_id
+    }
+
+    /// Makes up a new regtest P2WPKH address.
+    fn new_address(&mut self) -> String {
+        format!("bcrt1qmock{:032}", self.next_id())
+    }
+
+    /// Adds a confirmed coin paying the address to the wallet, returning its txid.
+    pub fn deposit(&mut self, address: &str, amount: Amount, confirmations: u64) -> String {
+        let txid = format!("{:064x}", self.next_id());
+        self.utxos.push(MockUtxo {
+            txid: txid.clone(),
+            vout: 0,
+            address: address.to_owned(),
+            amount,
+            confirmations,
+        });
+
+        txid
+    }
+
+    /// Makes the method fail with the given bitcoind error code.
+    pub fn fail(&mut self, method: &str, code: i64, message: &str) {
+        self.failures
+            .insert(method.to_owned(), (code, message.to_owned()));
+    }
+
+    /// Runs a single call against the wallet.
+    fn dispatch(&mut self, method: &str, params: &[Value]) -> Result<Value, (i64, String)> {
+        self.calls.push(method.to_owned());
+
+        if let Some(failure) = self.failures.get(method) {
+            return Err(failure.clone());
+        }
+
+        match method {
+            "getnewaddress" => {
+                let label = params.first().and_then(Value::as_str).unwrap_or_default();
+                let address_type = params.get(1).and_then(Value::as_str).unwrap_or("bech32");
+                let addr = self.new_address();
+                self.addresses
+                    .insert(addr.clone(), (label.to_owned(), address_type.to_owned()));
+
+                Ok(json!(addr))
+            }
+            "getaddressesbylabel" => {
+                let label = params.first().and_then(Value::as_str).unwrap_or_default();
+
+                Ok(Value::Object(
+                    self.addresses
+                        .iter()
+                        .filter(|(_, (addr_label, _))| addr_label == label)
+                        .map(|(addr, _)| (addr.clone(), json!({ "purpose": "receive" })))
+                        .collect(),
+                ))
+            }
+            "listunspent" => {
+                let minconf = params.first().and_then(Value::as_u64).unwrap_or(1);
+                let maxconf = params.get(1).and_then(Value::as_u64).unwrap_or(9999999);
+                let addrs: Vec<&str> = params
+                    .get(2)
+                    .and_then(Value::as_array)
+                    .map(|addrs| addrs.iter().filter_map(Value::as_str).collect())
+                    .unwrap_or_default();
+
+                Ok(self
+                    .utxos
+                    .iter()
+                    .filter(|utxo| (minconf..=maxconf).contains(&utxo.confirmations))
+                    .filter(|utxo| addrs.is_empty() || addrs.contains(&utxo.address.as_str()))
+                    .map(|utxo| {
+                        json!({
+                            "txid": utxo.txid,
+                            "vout": utxo.vout,
+                            "address": utxo.address,
+                            "scriptPubKey": format!("0014{}", "00".repeat(20)),
+                            // bitcoind sends amounts as JSON numbers
+                            "amount": utxo.amount.to_string().parse::<f64>().unwrap(),
+                            "confirmations": utxo.confirmations,
+                        })
+                    })
+                    .collect())
+            }
+            "estimatesmartfee" => Ok(match self.fee_rate {
+                Some(feerate) => json!({ "feerate": feerate, "blocks": 2 }),
+                None => json!({ "errors": ["Insufficient data or no feerate found"], "blocks": 0 }),
+            }),
+            "createrawtransaction" => {
+                let inputs: Vec<(String, u32)> = params
+                    .first()
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .map(|input| {
+                        (
+                            input["txid"].as_str().unwrap_or_default().to_owned(),
+                            input["vout"].as_u64().unwrap_or_default() as u32,
+                        )
+                    })
+                    .collect();
+                let outputs: Vec<(String, Amount)> = params
+                    .get(1)
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_object)
+                    .flatten()
+                    .map(|(addr, amount)| {
+                        let amount = match amount {
+                            Value::String(amount) => Amount::from_btc_str(amount),
+                            amount => Amount::from_btc_f64(amount.as_f64().unwrap_or(-1.0)),
+                        }
+                        .map_err(|e| (-3, e))?;
+
+                        Ok((addr.clone(), amount))
+                    })
+                    .collect::<Result<_, _>>()?;
+
+                let hex = format!("02000000{:056x}", self.next_id());
+                self.drafts.insert(hex.clone(), MockTx { inputs, outputs });
+
+                Ok(json!(hex))
+            }
+            "signrawtransactionwithwallet" => {
+                let hex = params.first().and_then(Value::as_str).unwrap_or_default();
+                if !self.drafts.contains_key(hex) {
+                    return Err((-22, "TX decode failed".to_owned()));
+                }
+
+                Ok(if self.can_sign {
+                    json!({ "hex": hex, "complete": true })
+                } else {
+                    json!({
+                        "hex": hex,
+                        "complete": false,
+                        "errors": [{ "error": "Unable to sign input" }],
+                    })
+                })
+            }
+            "sendrawtransaction" => {
+                let hex = params.first().and_then(Value::as_str).unwrap_or_default();
+                let tx = self
+                    .drafts
+                    .get(hex)
+                    .cloned()
+                    .ok_or_else(|| (-22, "TX decode failed".to_owned()))?;
+
+                // Every input must still be unspent
+                for (txid, vout) in &tx.inputs {
+                    if !self
+                        .utxos
+                        .iter()
+                        .any(|utxo| &utxo.txid == txid && utxo.vout == *vout)
+                    {
+                        return Err((
+                            RPC_INVALID_ADDRESS_OR_KEY,
+                            "bad-txns-inputs-missingorspent".to_owned(),
+                        ));
+                    }
+                }
+
+                self.utxos.retain(|utxo| {
+                    !tx.inputs
+                        .iter()
+                        .any(|(txid, vout)| &utxo.txid == txid && utxo.vout == *vout)
+                });
+
+                let txid = format!("{:064x}", self.next_id());
+                self.sent.push((txid.clone(), tx));
+
+                Ok(json!(txid))
+            }
+            _ => Err((RPC_METHOD_NOT_FOUND, "Method not found".to_owned())),
+        }
+    }
+}
+
+/// An in-process stand-in for bitcoind's JSON-RPC interface, listening on a random local port
+/// for as long as the test runs, so that anything talking to a node can be tested offline
+/// against a wallet whose contents the test controls.
+pub struct MockBitcoind {
+    url: String,
+    wallet: Arc<Mutex<MockWallet>>,
+}
+
+impl MockBitcoind {
+    /// Starts serving an empty wallet that can sign, estimating fees at 10 sat/vB.
+    pub fn start() -> Self {
+        let wallet = Arc::new(Mutex::new(MockWallet {
+            fee_rate: Some(0.0001),
+            can_sign: true,
+            ..MockWallet::default()
+        }));
+
+        let server_wallet = wallet.clone();
+        let addr = listen(move |req| handle(&req.body, &server_wallet));
+
+        Self {
+            url: format!("http://mock:mock@{}/", addr),
+            wallet,
+        }
+    }
+
+    /// Connects to the mock node.
+    pub fn connect(&self) -> RpcConnection {
+        RpcConnection::new(&self.url)
+    }
+
+    /// Takes payments through the mock node, with the default address type and fee settings.
+    pub fn backend(&self) -> Bitcoind {
+        Bitcoind::new(self.connect(), &Config::default())
+    }
+
+    /// Locks the wallet for inspection or changes. The lock must be released before making any
+    /// calls, or they will never be answered.
+    pub fn wallet(&self) -> MutexGuard<'_, MockWallet> {
+        self.wallet.lock().unwrap()
+    }
+}
+
+/// An HTTP request received by a mock node.
+pub struct MockRequest {
+    /// The method and path, e.g. "GET /v1/getinfo"
+    pub target: String,
+
+    /// Every header, by lowercase name
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Serves HTTP on a random local port from a background thread, answering every request with
+/// the handler's status and JSON response.
+fn listen<H>(handler: H) -> SocketAddr
+where
+    H: Fn(&MockRequest) -> (&'static str, Value) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("mock node could not bind");
+    let addr = listener.local_addr().expect("mock node has no address");
+    let handler = Arc::new(handler);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().filter_map(Result::ok) {
+            let handler = handler.clone();
+            thread::spawn(move || serve(stream, &*handler));
+        }
+    });
+
+    addr
+}
+
+/// Answers every request sent over the connection until the client hangs up.
+fn serve(stream: TcpStream, handler: &dyn Fn(&MockRequest) -> (&'static str, Value)) {
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(stream) => stream,
+        Err(_) => return,
+    });
+    let mut writer = stream;
+
+    while let Some(req) = read_request(&mut reader) {
+        let (status, resp) = handler(&req);
+        let resp = resp.to_string();
+
+        if write!(
+            writer,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            resp.len(),
+            resp
+        )
+        .and_then(|_| writer.flush())
+        .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Reads the next HTTP request on the connection, if any.
+fn read_request(reader: &mut impl BufRead) -> Option<MockRequest> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+
+    // The method and path, without the HTTP version
+    let target = line.trim_end().rsplitn(2, ' ').last()?.to_owned();
+
+    // Then headers up to a blank line
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    let content_length = match headers.get("content-length") {
+        Some(len) => len.parse().ok()?,
+        None => 0,
+    };
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(MockRequest {
+        target,
+        headers,
+        body,
+    })
+}
+
+/// Runs a JSON-RPC request, returning the HTTP status and response bitcoind would send.
+fn handle(body: &[u8], wallet: &Mutex<MockWallet>) -> (&'static str, Value) {
+    let req: Value = match serde_json::from_slice(body) {
+        Ok(req) => req,
+        Err(e) => {
+            return (
+                "500 Internal Server Error",
+                json!({ "error": e.to_string() }),
+            )
+        }
+    };
+
+    let method = req["method"].as_str().unwrap_or_default();
+    let params = req["params"].as_array().cloned().unwrap_or_default();
+
+    match wallet.lock().unwrap().dispatch(method, &params) {
+        Ok(result) => (
+            "200 OK",
+            json!({ "result": result, "error": null, "id": req["id"] }),
+        ),
+        Err((code, message)) => (
+            // Like bitcoind, unknown methods are a 404 and every other error a 500
+            if code == RPC_METHOD_NOT_FOUND {
+                "404 Not Found"
+            } else {
+                "500 Internal Server Error"
+            },
+            json!({
+                "result": null,
+                "error": { "code": code, "message": message },
+                "id": req["id"],
+            }),
+        ),
+    }
+}
+
+/// An output received by the mock monero wallet.
+#[derive(Clone, Debug)]
+pub struct MockOutput {
+    pub tx_hash: String,
+
+    /// The index of the subaddress in account 0 it was paid to
+    pub minor: u32,
+
+    /// In piconero
+    pub amount: u64,
+    pub block_height: u64,
+    pub key_image: String,
+    pub spent: bool,
+}
+
+/// Everything the mock monero wallet knows, which tests may inspect and change at any time.
+#[derive(Default, Debug)]
+pub struct MockMoneroWallet {
+    /// The label of every subaddress in account 0, by index. Index 0 is the primary address
+    pub labels: Vec<String>,
+    pub outputs: Vec<MockOutput>,
+    pub height: u64,
+
+    /// Sweeps relayed by sweep_single, as (txid, destination, piconero received)
+    pub sent: Vec<(String, String, u64)>,
+
+    /// The name of every method called, in order
+    pub calls: Vec<String>,
+
+    next_id: u64,
+}
+
+impl MockMoneroWallet {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+
+        self.next_id
+    }
+
+    /// The made-up address of the subaddress with the given index.
+    pub fn address(minor: u32) -> String {
+        format!("8mock{:08}", minor)
+    }
+
+    /// Adds an output paying the subaddress, with the given number of confirmations, to the
+    /// wallet, returning the txid of the transaction paying it. Outputs with no confirmations
+    /// are in the pool.
+    pub fn deposit(&mut self, minor: u32, piconero: u64, confirmations: u64) -> String {
+        let tx_hash = format!("{:064x}", self.next_id());
+        let key_image = self.next_key_image();
+        self.outputs.push(MockOutput {
+            tx_hash: tx_hash.clone(),
+            minor,
+            amount: piconero,
+            block_height: self.height - confirmations,
+            key_image,
+            spent: false,
+        });
+
+        tx_hash
+    }
+
+    /// Adds another output to an existing deposit transaction.
+    pub fn add_output(&mut self, tx_hash: &str, minor: u32, piconero: u64) {
+        let block_height = self
+            .outputs
+            .iter()
+            .find(|output| output.tx_hash == tx_hash)
+            .map(|output| output.block_height)
+            .unwrap_or(self.height);
+        let key_image = self.next_key_image();
+
+        self.outputs.push(MockOutput {
+            tx_hash: tx_hash.to_owned(),
+            minor,
+            amount: piconero,
+            block_height,
+            key_image,
+            spent: false,
+        });
+    }
+
+    /// Makes up the key image of a new output.
+    fn next_key_image(&mut self) -> String {
+        format!("{:064x}", u64::MAX - self.next_id())
+    }
+
+    /// Determines whether or not the output is still waiting in the pool.
+    fn in_pool(&self, output: &MockOutput) -> bool {
+        output.block_height >= self.height
+    }
+
+    /// Determines whether or not the output is 10 blocks deep, and so spendable.
+    fn unlocked(&self, output: &MockOutput) -> bool {
+        self.height - output.block_height >= 10
+    }
+
+    /// Runs a single call against the wallet.
+    fn dispatch(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        self.calls.push(method.to_owned());
+
+        if params["account_index"].as_u64().unwrap_or_default() != 0 {
+            return Err((-13, "account index is out of bound".to_owned()));
+        }
+
+        let subaddr_indices: Vec<u32> = params["subaddr_indices"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_u64)
+            .map(|minor| minor as u32)
+            .collect();
+
+        match method {
+            "create_address" => {
+                self.labels
+                    .push(params["label"].as_str().unwrap_or_default().to_owned());
+                let minor = self.labels.len() as u32 - 1;
+
+                Ok(json!({ "address": Self::address(minor), "address_index": minor }))
+            }
+            "get_address_index" => params["address"]
+                .as_str()
+                .and_then(|addr| addr.strip_prefix("8mock"))
+                .and_then(|minor| minor.parse::<u32>().ok())
+                .filter(|minor| (*minor as usize) < self.labels.len())
+                .map(|minor| json!({ "index": { "major": 0, "minor": minor } }))
+                .ok_or_else(|| (-2, "Address doesn't belong to the wallet".to_owned())),
+            "get_height" => Ok(json!({ "height": self.height })),
+            "incoming_transfers" => {
+                let transfers: Vec<Value> = self
+                    .outputs
+                    .iter()
+                    .filter(|output| !output.spent && !self.in_pool(output))
+                    .filter(|output| {
+                        subaddr_indices.is_empty() || subaddr_indices.contains(&output.minor)
+                    })
+                    .map(|output| {
+                        json!({
+                            "tx_hash": output.tx_hash,
+                            "subaddr_index": { "major": 0, "minor": output.minor },
+                            "amount": output.amount,
+                            "block_height": output.block_height,
+                            "key_image": output.key_image,
+                            "unlocked": self.unlocked(output),
+                            "frozen": false,
+                            "spent": false,
+                        })
+                    })
+                    .collect();
+
+                // Like the real wallet, there's no list at all when there are no transfers
+                Ok(if transfers.is_empty() {
+                    json!({})
+                } else {
+                    json!({ "transfers": transfers })
+                })
+            }
+            "get_transfers" => {
+                let pool_requested = params["pool"].as_bool().unwrap_or_default();
+                let pool: Vec<Value> = self
+                    .outputs
+                    .iter()
+                    .filter(|output| pool_requested && self.in_pool(output))
+                    .filter(|output| {
+                        subaddr_indices.is_empty() || subaddr_indices.contains(&output.minor)
+                    })
+                    .map(|output| {
+                        json!({
+                            "txid": output.tx_hash,
+                            "subaddr_index": { "major": 0, "minor": output.minor },
+                            "amount": output.amount,
+                            "confirmations": 0,
+                            "type": "pool",
+                        })
+                    })
+                    .collect();
+
+                Ok(if pool.is_empty() {
+                    json!({})
+                } else {
+                    json!({ "pool": pool })
+                })
+            }
+            "sweep_single" => {
+                let destination = params["address"].as_str().unwrap_or_default().to_owned();
+                let key_image = params["key_image"].as_str().unwrap_or_default();
+                let i = self
+                    .outputs
+                    .iter()
+                    .position(|output| {
+                        output.key_image == key_image && !output.spent && self.unlocked(output)
+                    })
+                    .ok_or_else(|| (-38, "No outputs found".to_owned()))?;
+                if self.outputs[i].amount <= MockMonero::FEE {
+                    return Err((-37, "not enough money".to_owned()));
+                }
+
+                let amount = self.outputs[i].amount - MockMonero::FEE;
+                let tx_hash = format!("{:064x}", self.next_id());
+                if !params["do_not_relay"].as_bool().unwrap_or_default() {
+                    self.outputs[i].spent = true;
+                    self.sent.push((tx_hash.clone(), destination, amount));
+                }
+
+                Ok(json!({
+                    "tx_hash": tx_hash,
+                    "amount": amount,
+                    "fee": MockMonero::FEE,
+                    "weight": 1500,
+                    "tx_blob": format!("02{}", tx_hash),
+                }))
+            }
+            _ => Err((-32601, "Method not found".to_owned())),
+        }
+    }
+}
+
+/// An in-process stand-in for monero-wallet-rpc, like MockBitcoind, holding a single account.
+pub struct MockMonero {
+    url: String,
+    wallet: Arc<Mutex<MockMoneroWallet>>,
+}
+
+impl MockMonero {
+    /// The fee paid by every sweep transaction, each spending a single output, in piconero.
+    pub const FEE: u64 = 30_000_000;
+
+    /// Starts serving an empty wallet at height 1000.
+    pub fn start() -> Self {
+        let wallet = Arc::new(Mutex::new(MockMoneroWallet {
+            labels: vec!["Primary account".to_owned()],
+            height: 1000,
+            ..MockMoneroWallet::default()
+        }));
+
+        let server_wallet = wallet.clone();
+        let addr = listen(move |req| handle_monero(&req.body, &server_wallet));
+
+        Self {
+            url: format!("http://{}/json_rpc", addr),
+            wallet,
+        }
+    }
+
+    /// Takes payments through the mock wallet.
+    pub fn backend(&self) -> MoneroWallet {
+        MoneroWallet::new(&self.url, 0, 1)
+    }
+
+    /// Locks the wallet for inspection or changes. The lock must be released before making any
+    /// calls, or they will never be answered.
+    pub fn wallet(&self) -> MutexGuard<'_, MockMoneroWallet> {
+        self.wallet.lock().unwrap()
+    }
+}
+
+/// Runs a JSON-RPC 2.0 request, returning the HTTP status and response monero-wallet-rpc would
+/// send. RPC errors are reported with a 200.
+fn handle_monero(body: &[u8], wallet: &Mutex<MockMoneroWallet>) -> (&'static str, Value) {
+    let req: Value = match serde_json::from_slice(body) {
+        Ok(req) => req,
+        Err(e) => return ("400 Bad Request", json!({ "error": e.to_string() })),
+    };
+
+    let method = req["method"].as_str().unwrap_or_default();
+    let resp = match wallet.lock().unwrap().dispatch(method, &req["params"]) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": req["id"], "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": req["id"],
+            "error": { "code": code, "message": message },
+        }),
+    };
+
+    ("200 OK", resp)
+}
+
+/// An invoice issued by the mock LND node.
+#[derive(Clone, Debug)]
+pub struct MockInvoice {
+    /// The payment hash, in hex
+    pub r_hash: String,
+    pub payment_request: String,
+    pub memo: String,
+
+    /// In satoshis
+    pub value: u64,
+    pub expiry: u64,
+
+    /// "OPEN", "SETTLED" or "CANCELED"
+    pub state: &'static str,
+    pub amt_paid_sat: u64,
+}
+
+/// Everything the mock LND node knows, which tests may inspect and change at any time.
+#[derive(Default, Debug)]
+pub struct MockLndNode {
+    pub invoices: Vec<MockInvoice>,
+
+    /// The target of every request, in order
+    pub calls: Vec<String>,
+
+    /// Fails every request, as a node that is still starting up would
+    pub down: bool,
+
+    next_id: u64,
+}
+
+impl MockLndNode {
+    fn invoice(&mut self, r_hash: &str) -> &mut MockInvoice {
+        self.invoices
+            .iter_mut()
+            .find(|invoice| invoice.r_hash == r_hash)
+            .expect("no such invoice")
+    }
+
+    /// Pays the invoice in full.
+    pub fn settle(&mut self, r_hash: &str) {
+        let invoice = self.invoice(r_hash);
+        invoice.state = "SETTLED";
+        invoice.amt_paid_sat = invoice.value;
+    }
+
+    /// Cancels the invoice, as LND does once it expires.
+    pub fn cancel(&mut self, r_hash: &str) {
+        self.invoice(r_hash).state = "CANCELED";
+    }
+
+    /// Runs a single REST call against the node.
+    fn dispatch(&mut self, req: &MockRequest) -> Result<Value, (&'static str, String)> {
+        self.calls.push(req.target.clone());
+
+        if self.down {
+            return Err((
+                "503 Service Unavailable",
+                "server is still starting".to_owned(),
+            ));
+        }
+
+        let macaroon = req
+            .headers
+            .get("grpc-metadata-macaroon")
+            .map(String::as_str);
+        if macaroon != Some(MockLnd::MACAROON_HEX) {
+            return Err((
+                "500 Internal Server Error",
+                "verification failed: signature mismatch after caveat verification".to_owned(),
+            ));
+        }
+
+        if req.target == "POST /v1/invoices" {
+            let params: Value = serde_json::from_slice(&req.body)
+                .map_err(|e| ("400 Bad Request", e.to_string()))?;
+            // LND encodes 64-bit integers as strings, but accepts either
+            let int = |field: &str| match &params[field] {
+                Value::String(n) => n.parse().unwrap_or_default(),
+                n => n.as_u64().unwrap_or_default(),
+            };
+
+            self.next_id += 1;
+            let r_hash = format!("{:064x}", self.next_id);
+            let invoice = MockInvoice {
+                r_hash: r_hash.clone(),
+                payment_request: format!("lnbcrt{}n1mock{}", int("value") * 10, self.next_id),
+                memo: params["memo"].as_str().unwrap_or_default().to_owned(),
+                value: int("value"),
+                expiry: int("expiry"),
+                state: "OPEN",
+                amt_paid_sat: 0,
+            };
+            self.invoices.push(invoice.clone());
+
+            let r_hash_bytes: Vec<u8> = (0..32)
+                .map(|i| u8::from_str_radix(&r_hash[2 * i..2 * i + 2], 16).unwrap())
+                .collect();
+
+            return Ok(json!({
+                "r_hash": base64::encode(&r_hash_bytes),
+                "payment_request": invoice.payment_request,
+                "add_index": self.next_id.to_string(),
+            }));
+        }
+
+        match req.target.strip_prefix("GET /v1/invoice/") {
+            Some(r_hash) => self
+                .invoices
+                .iter()
+                .find(|invoice| invoice.r_hash == r_hash)
+                .map(|invoice| {
+                    json!({
+                        "r_hash": invoice.r_hash,
+                        "payment_request": invoice.payment_request,
+                        "value": invoice.value.to_string(),
+                        "state": invoice.state,
+                        "amt_paid_sat": invoice.amt_paid_sat.to_string(),
+                    })
+                })
+                .ok_or(("404 Not Found", "unable to locate invoice".to_owned())),
+            None => Err(("404 Not Found", "Not Found".to_owned())),
+        }
+    }
+}
+
+/// An in-process stand-in for LND's REST interface, over plain HTTP.
+pub struct MockLnd {
+    url: String,
+    node: Arc<Mutex<MockLndNode>>,
+}
+
+impl MockLnd {
+    /// The macaroon the node expects, and its hex encoding as sent in requests.
+    pub const MACAROON: &'static [u8] = b"onionfans";
+    pub const MACAROON_HEX: &'static str = "6f6e696f6e66616e73";
+
+    /// Starts serving a node without any invoices.
+    pub fn start() -> Self {
+        let node = Arc::new(Mutex::new(MockLndNode::default()));
+
+        let server_node = node.clone();
+        let addr = listen(move |req| match server_node.lock().unwrap().dispatch(req) {
+            Ok(resp) => ("200 OK", resp),
+            Err((status, message)) => (status, json!({ "code": 2, "message": message })),
+        });
+
+        Self {
+            url: format!("http://{}", addr),
+            node,
+        }
+    }
+
+    /// Issues invoices through the mock node, valid for an hour.
+    pub fn backend(&self) -> Lnd {
+        Lnd::new(&self.url, Self::MACAROON, None, 3600).unwrap()
+    }
+
+    /// Locks the node for inspection or changes. The lock must be released before making any
+    /// calls, or they will never be answered.
+    pub fn node(&self) -> MutexGuard<'_, MockLndNode> {
+        self.node.lock().unwrap()
+    }
+}
@@ -0,0 +1,721 @@
+use super::{
+    amount::Amount,
+    backend::{Deposit, PaymentBackend, PaymentError, SweepError},
+    config::Config,
+    sweep::{SweepPlan, SweepTx},
+};
+
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use awc::Client;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// monero-wallet-rpc's WALLET_RPC_ERROR_CODE_WRONG_ADDRESS, returned for addresses that don't
+/// belong to the wallet.
+const WRONG_ADDRESS: i64 = -2;
+
+/// Converts an amount of piconero into an Amount. With the monero backend, amounts count
+/// piconero exactly.
+pub fn from_piconero(piconero: u64) -> Amount {
+    Amount::from_sat(piconero)
+}
+
+/// An error encountered while calling monero-wallet-rpc.
+#[derive(Debug)]
+enum WalletError {
+    /// The wallet could not be reached
+    Transport(String),
+
+    /// The wallet responded with an HTTP error and no RPC error body
+    Http(u16, String),
+
+    /// The wallet rejected the call
+    Rpc { code: i64, message: String },
+
+    /// The wallet's response could not be understood
+    Decode(String),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "could not reach monero-wallet-rpc: {}", e),
+            Self::Http(status, body) => {
+                write!(f, "monero-wallet-rpc returned HTTP {}: {}", status, body)
+            }
+            Self::Rpc { code, message } => {
+                write!(f, "monero-wallet-rpc error {}: {}", code, message)
+            }
+            Self::Decode(e) => write!(f, "invalid monero-wallet-rpc response: {}", e),
+        }
+    }
+}
+
+impl From<WalletError> for PaymentError {
+    fn from(e: WalletError) -> Self {
+        match e {
+            WalletError::Transport(_) => Self::Unavailable(e.to_string()),
+
+            // The wallet was started with --rpc-login, which isn't supported
+            WalletError::Http(status, _) if status == StatusCode::UNAUTHORIZED.as_u16() => {
+                Self::Misconfigured(e.to_string())
+            }
+            WalletError::Http(..) | WalletError::Rpc { .. } | WalletError::Decode(_) => {
+                Self::Failed(e.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RpcRequest<'a> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'a str,
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct RpcErrorObject {
+    code: i64,
+    message: String,
+}
+
+/// The position of a subaddress in the wallet: its account, and its index within the account.
+#[derive(Deserialize, Clone, Copy, Debug)]
+struct SubaddressIndex {
+    major: u32,
+    minor: u32,
+}
+
+#[derive(Deserialize)]
+struct NewAddress {
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct AddressIndex {
+    index: SubaddressIndex,
+}
+
+#[derive(Deserialize)]
+struct Height {
+    height: u64,
+}
+
+#[derive(Deserialize)]
+struct IncomingTransfers {
+    /// Omitted entirely when there are no transfers
+    #[serde(default)]
+    transfers: Vec<IncomingTransfer>,
+}
+
+/// An unspent output received by the wallet, as returned by incoming_transfers.
+#[derive(Deserialize, Clone, Debug)]
+struct IncomingTransfer {
+    tx_hash: String,
+    subaddr_index: SubaddressIndex,
+
+    /// In piconero
+    amount: u64,
+    block_height: u64,
+
+    /// Identifies the output when it is spent. Missing for outputs in the pool
+    #[serde(default)]
+    key_image: String,
+
+    /// Outputs can't be spent until they are 10 blocks deep
+    unlocked: bool,
+    #[serde(default)]
+    frozen: bool,
+}
+
+#[derive(Deserialize)]
+struct Transfers {
+    /// Omitted entirely when nothing is waiting in the pool
+    #[serde(default)]
+    pool: Vec<PoolTransfer>,
+}
+
+/// A transfer received by the wallet that is still waiting in the transaction pool, as returned
+/// by get_transfers.
+#[derive(Deserialize, Debug)]
+struct PoolTransfer {
+    txid: String,
+    subaddr_index: SubaddressIndex,
+
+    /// In piconero
+    amount: u64,
+}
+
+/// The result of sweep_single: a transaction spending a single output.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Sweep {
+    tx_hash: String,
+
+    /// The amount received by the destination, in piconero
+    amount: u64,
+    weight: u64,
+    tx_blob: String,
+}
+
+/// Takes payments in monero through a monero-wallet-rpc wallet, giving every deposit address its
+/// own subaddress in one account of the wallet. The wallet must be started with
+/// --disable-rpc-login, since it only supports digest authentication, so it should only listen
+/// on localhost.
+pub struct MoneroWallet {
+    url: String,
+    client: Client,
+    account_index: u32,
+
+    /// The confirmations a deposit needs before it is swept
+    min_confirmations: u32,
+    next_id: AtomicU64,
+}
+
+impl MoneroWallet {
+    /// Connects to the wallet RPC endpoint at the given URL, e.g.
+    /// http://127.0.0.1:18082/json_rpc, taking deposits into the given account and sweeping
+    /// them once they have min_confirmations.
+    pub fn new(url: &str, account_index: u32, min_confirmations: u32) -> Self {
+        Self {
+            url: url.to_owned(),
+            client: Client::new(),
+            account_index,
+            min_confirmations,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Connects to the wallet configured for the store.
+    pub fn from_config(cfg: &Config) -> Result<Self, String> {
+        Ok(Self::new(
+            &cfg.monero_rpc_url,
+            cfg.monero_account_index,
+            cfg.min_confirmations,
+        ))
+    }
+
+    /// Calls the given wallet RPC method, decoding its result into T.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, WalletError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut resp = self
+            .client
+            .post(&self.url)
+            .send_json(&RpcRequest {
+                jsonrpc: "2.0",
+                id,
+                method,
+                params,
+            })
+            .await
+            .map_err(|e| WalletError::Transport(e.to_string()))?;
+        let status = resp.status();
+        let body = resp
+            .body()
+            .await
+            .map_err(|e| WalletError::Transport(e.to_string()))?;
+
+        if !status.is_success() {
+            return Err(WalletError::Http(
+                status.as_u16(),
+                String::from_utf8_lossy(&body).into_owned(),
+            ));
+        }
+
+        let decoded: RpcResponse<T> =
+            serde_json::from_slice(&body).map_err(|e| WalletError::Decode(e.to_string()))?;
+
+        if let Some(RpcErrorObject { code, message }) = decoded.error {
+            return Err(WalletError::Rpc { code, message });
+        }
+
+        decoded.result.ok_or(WalletError::Decode(format!(
+            "{} returned no result",
+            method
+        )))
+    }
+
+    /// Finds the subaddress index of each of the given addresses in the store's account.
+    /// Addresses that aren't subaddresses of the account, such as bitcoin addresses generated
+    /// before the store switched to monero, are skipped.
+    async fn subaddresses(&self, addrs: &[&str]) -> Result<BTreeMap<u32, String>, WalletError> {
+        let mut indices = BTreeMap::new();
+
+        for addr in addrs {
+            match self
+                .call::<AddressIndex>("get_address_index", json!({ "address": addr }))
+                .await
+            {
+                Ok(AddressIndex { index }) if index.major == self.account_index => {
+                    indices.insert(index.minor, (*addr).to_owned());
+                }
+                Ok(_) => {}
+                Err(WalletError::Rpc { code, .. }) if code == WRONG_ADDRESS => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(indices)
+    }
+
+    /// Gets every unspent output received by the given subaddresses.
+    async fn outputs(
+        &self,
+        subaddresses: &BTreeMap<u32, String>,
+    ) -> Result<Vec<IncomingTransfer>, WalletError> {
+        // The wallet treats an empty subaddress filter as no filter at all
+        if subaddresses.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let incoming: IncomingTransfers = self
+            .call(
+                "incoming_transfers",
+                json!({
+                    "transfer_type": "available",
+                    "account_index": self.account_index,
+                    "subaddr_indices": subaddresses.keys().collect::<Vec<_>>(),
+                }),
+            )
+            .await?;
+
+        Ok(incoming.transfers)
+    }
+
+    /// Gets every transfer to the given subaddresses still waiting in the transaction pool.
+    async fn pool(
+        &self,
+        subaddresses: &BTreeMap<u32, String>,
+    ) -> Result<Vec<PoolTransfer>, WalletError> {
+        if subaddresses.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let transfers: Transfers = self
+            .call(
+                "get_transfers",
+                json!({
+                    "pool": true,
+                    "account_index": self.account_index,
+                    "subaddr_indices": subaddresses.keys().collect::<Vec<_>>(),
+                }),
+            )
+            .await?;
+
+        Ok(transfers.pool)
+    }
+
+    /// Sweeps the output into the destination, only building the transaction unless relay is
+    /// set. Outputs are swept one at a time by their key images, so that nothing but the planned
+    /// outputs is ever spent.
+    async fn sweep(
+        &self,
+        output: &IncomingTransfer,
+        destination: &str,
+        relay: bool,
+    ) -> Result<Sweep, WalletError> {
+        self.call(
+            "sweep_single",
+            json!({
+                "address": destination,
+                "key_image": output.key_image,
+                "do_not_relay": !relay,
+                "get_tx_hex": true,
+            }),
+        )
+        .await
+    }
+
+    /// Finds the outputs making up the planned transaction's inputs, failing if any of them is
+    /// no longer unspent.
+    async fn planned_outputs(&self, tx: &SweepTx) -> Result<Vec<IncomingTransfer>, PaymentError> {
+        let subaddresses: BTreeMap<u32, String> = tx
+            .inputs
+            .iter()
+            .map(|deposit| (deposit.vout, deposit.address.clone().unwrap_or_default()))
+            .collect();
+        let outputs: Vec<IncomingTransfer> = self
+            .outputs(&subaddresses)
+            .await?
+            .into_iter()
+            .filter(|output| {
+                tx.inputs.iter().any(|deposit| {
+                    deposit.txid == output.tx_hash && deposit.vout == output.subaddr_index.minor
+                })
+            })
+            .collect();
+
+        for deposit in &tx.inputs {
+            if !outputs.iter().any(|output| {
+                output.tx_hash == deposit.txid && output.subaddr_index.minor == deposit.vout
+            }) {
+                return Err(PaymentError::Failed(format!(
+                    "planned deposit {} is no longer unspent",
+                    deposit.id()
+                )));
+            }
+        }
+
+        Ok(outputs)
+    }
+}
+
+/// Groups outputs into deposits, one per transaction and subaddress, since a single payment may
+/// be split across several outputs.
+fn group_deposits(
+    outputs: &[IncomingTransfer],
+    subaddresses: &BTreeMap<u32, String>,
+    height: u64,
+) -> Vec<Deposit> {
+    let mut deposits: Vec<(Deposit, u64)> = Vec::new();
+
+    for output in outputs {
+        let minor = output.subaddr_index.minor;
+
+        match deposits
+            .iter_mut()
+            .find(|(deposit, _)| deposit.txid == output.tx_hash && deposit.vout == minor)
+        {
+            Some((_, piconero)) => *piconero += output.amount,
+            None => deposits.push((
+                Deposit {
+                    txid: output.tx_hash.clone(),
+                    vout: minor,
+                    address: subaddresses.get(&minor).cloned(),
+                    amount: Amount::ZERO,
+                    confirmations: height.saturating_sub(output.block_height),
+                },
+                output.amount,
+            )),
+        }
+    }
+
+    deposits
+        .into_iter()
+        .map(|(deposit, piconero)| Deposit {
+            amount: from_piconero(piconero),
+            ..deposit
+        })
+        .collect()
+}
+
+#[async_trait(?Send)]
+impl PaymentBackend for MoneroWallet {
+    async fn new_deposit_address(&self, label: &str) -> Result<String, PaymentError> {
+        let addr: NewAddress = self
+            .call(
+                "create_address",
+                json!({ "account_index": self.account_index, "label": label }),
+            )
+            .await?;
+
+        Ok(addr.address)
+    }
+
+    async fn deposits(&self, addrs: &[&str]) -> Result<Vec<Deposit>, PaymentError> {
+        let subaddresses = self.subaddresses(addrs).await?;
+        let mut outputs = self.outputs(&subaddresses).await?;
+        let pool = self.pool(&subaddresses).await?;
+        if outputs.is_empty() && pool.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let Height { height } = self.call("get_height", json!({})).await?;
+
+        // Transfers in the pool are counted as outputs with no confirmations yet
+        outputs.extend(pool.into_iter().map(|transfer| IncomingTransfer {
+            tx_hash: transfer.txid,
+            subaddr_index: transfer.subaddr_index,
+            amount: transfer.amount,
+            block_height: height,
+            key_image: String::new(),
+            unlocked: false,
+            frozen: false,
+        }));
+
+        Ok(group_deposits(&outputs, &subaddresses, height))
+    }
+
+    async fn plan_sweep(
+        &self,
+        addrs: &[&str],
+        credited: &HashSet<String>,
+        destination: &str,
+    ) -> Result<SweepPlan, PaymentError> {
+        let subaddresses = self.subaddresses(addrs).await?;
+        let mut spendable = self.outputs(&subaddresses).await?;
+        spendable.retain(|output| {
+            output.unlocked
+                && !output.frozen
+                && credited.contains(&format!(
+                    "{}:{}",
+                    output.tx_hash, output.subaddr_index.minor
+                ))
+        });
+
+        let mut plan = SweepPlan {
+            destination: destination.to_owned(),
+            fee_rate: 0,
+            txs: Vec::new(),
+            dust: Vec::new(),
+        };
+        if spendable.is_empty() {
+            return Ok(plan);
+        }
+
+        let Height { height } = self.call("get_height", json!({})).await?;
+        spendable.retain(|output| {
+            height.saturating_sub(output.block_height) >= u64::from(self.min_confirmations)
+        });
+        let mut weight = 0;
+
+        // Each deposit is swept separately, an output at a time, so every transaction's inputs
+        // are known
+        for deposit in group_deposits(&spendable, &subaddresses, height) {
+            let mut piconero = 0;
+            let mut vsize = 0;
+            for output in spendable.iter().filter(|output| {
+                output.tx_hash == deposit.txid && output.subaddr_index.minor == deposit.vout
+            }) {
+                let draft = self.sweep(output, destination, false).await?;
+                piconero += draft.amount;
+                vsize += draft.weight;
+            }
+            let value = from_piconero(piconero);
+            weight += vsize;
+
+            plan.txs.push(SweepTx {
+                total: deposit.amount,
+                fee: deposit.amount.saturating_sub(value),
+                value,
+                vsize,
+                inputs: vec![deposit],
+            });
+        }
+
+        // Monero fees are set by the wallet, so the rate is only reported
+        if weight > 0 {
+            plan.fee_rate = plan.fee().as_sat().div_ceil(weight);
+        }
+
+        Ok(plan)
+    }
+
+    async fn draft_sweep(&self, plan: &SweepPlan) -> Result<Vec<String>, PaymentError> {
+        let mut txs = Vec::new();
+
+        for tx in &plan.txs {
+            // Deposits split across several outputs take a transaction per output to sweep
+            let mut blobs = Vec::new();
+            for output in self.planned_outputs(tx).await? {
+                blobs.push(self.sweep(&output, &plan.destination, false).await?.tx_blob);
+            }
+
+            txs.push(blobs.join(","));
+        }
+
+        Ok(txs)
+    }
+
+    async fn execute_sweep(&self, plan: &SweepPlan) -> Result<Vec<String>, SweepError> {
+        let mut txids = Vec::new();
+
+        for tx in &plan.txs {
+            let outputs = match self.planned_outputs(tx).await {
+                Ok(outputs) => outputs,
+                Err(error) => {
+                    return Err(SweepError {
+                        broadcast: txids,
+                        error,
+                    })
+                }
+            };
+
+            for output in outputs {
+                match self.sweep(&output, &plan.destination, true).await {
+                    Ok(sweep) => txids.push(sweep.tx_hash),
+                    Err(e) => {
+                        return Err(SweepError {
+                            broadcast: txids,
+                            error: e.into(),
+                        })
+                    }
+                }
+            }
+        }
+
+        Ok(txids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{backend::BackendKind, mock_rpc::MockMonero};
+
+    #[test]
+    fn test_from_piconero() {
+        // Nothing is rounded away
+        assert_eq!(
+            BackendKind::Monero.format_amount(from_piconero(1_123_456_789_123)),
+            "1.123456789123"
+        );
+        assert_eq!(from_piconero(9_999), Amount::from_sat(9_999));
+    }
+
+    #[actix_rt::test]
+    async fn test_deposits_by_subaddress() {
+        let node = MockMonero::start();
+        let wallet = node.backend();
+
+        let addr = wallet.new_deposit_address("onionfans:lol").await.unwrap();
+        let other = wallet.new_deposit_address("onionfans:other").await.unwrap();
+        assert_eq!(node.wallet().labels[1], "onionfans:lol");
+        {
+            let mut mock = node.wallet();
+
+            // One payment split over two outputs, and someone else's
+            let txid = mock.deposit(1, 300_000_000, 20);
+            mock.add_output(&txid, 1, 200_000_000);
+            mock.deposit(2, 700_000_000, 20);
+
+            // Still in the pool
+            mock.deposit(2, 100_000_000, 0);
+        }
+
+        // Bitcoin addresses from before the switch are ignored
+        let deposits = wallet.deposits(&[&addr, "bcrt1qold"]).await.unwrap();
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits[0].vout, 1);
+        assert_eq!(deposits[0].address, Some(addr.clone()));
+        assert_eq!(deposits[0].amount, Amount::from_sat(500_000_000));
+        assert_eq!(deposits[0].confirmations, 20);
+
+        // Deposits in the pool are seen, but aren't creditable
+        let deposits = wallet.deposits(&[&other]).await.unwrap();
+        assert_eq!(deposits.len(), 2);
+        assert_eq!(deposits[1].amount, Amount::from_sat(100_000_000));
+        assert_eq!(deposits[1].confirmations, 0);
+        assert_eq!(
+            wallet
+                .deposits(&[&other])
+                .await
+                .unwrap()
+                .iter()
+                .filter(|deposit| deposit.is_confirmed(&Config::default()))
+                .map(|deposit| deposit.amount)
+                .sum::<Amount>(),
+            Amount::from_sat(700_000_000)
+        );
+        assert!(wallet.deposits(&[]).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_sweep_unlocked_subaddresses() {
+        let node = MockMonero::start();
+        let wallet = node.backend();
+        let addr = wallet.new_deposit_address("onionfans:lol").await.unwrap();
+        let unlisted = wallet
+            .new_deposit_address("onionfans:unlisted")
+            .await
+            .unwrap();
+        {
+            let mut mock = node.wallet();
+            mock.deposit(1, 500_000_000, 20);
+
+            // Not yet spendable, and not a deposit the store asked about
+            mock.deposit(1, 100_000_000, 2);
+            mock.deposit(2, 900_000_000, 20);
+        }
+
+        // Deposits that haven't been credited are never swept
+        assert!(wallet
+            .plan_sweep(&[&addr], &HashSet::new(), "4store")
+            .await
+            .unwrap()
+            .txs
+            .is_empty());
+
+        let credited = wallet
+            .deposits(&[&addr])
+            .await
+            .unwrap()
+            .iter()
+            .map(Deposit::id)
+            .collect();
+        let plan = wallet
+            .plan_sweep(&[&addr], &credited, "4store")
+            .await
+            .unwrap();
+        assert_eq!(plan.txs.len(), 1);
+        assert_eq!(plan.total(), Amount::from_sat(500_000_000));
+        assert_eq!(plan.fee(), from_piconero(MockMonero::FEE));
+        assert!(node.wallet().sent.is_empty());
+
+        // Outputs that unlock after the sweep is planned are left for the next one
+        let late = node.wallet().deposit(1, 200_000_000, 20);
+
+        let txids = wallet.execute_sweep(&plan).await.unwrap();
+        assert_eq!(txids.len(), 1);
+
+        {
+            let mock = node.wallet();
+            assert_eq!(
+                mock.sent,
+                vec![(
+                    txids[0].clone(),
+                    "4store".to_owned(),
+                    500_000_000 - MockMonero::FEE
+                )]
+            );
+
+            // The locked output, the late output and the other subaddress are untouched
+            assert_eq!(
+                mock.outputs.iter().filter(|output| !output.spent).count(),
+                3
+            );
+            assert!(mock
+                .outputs
+                .iter()
+                .any(|output| output.tx_hash == late && !output.spent));
+        }
+
+        // A planned output that is gone by the time the sweep runs stops it
+        assert!(matches!(
+            wallet.execute_sweep(&plan).await,
+            Err(SweepError { ref broadcast, .. }) if broadcast.is_empty()
+        ));
+
+        assert_eq!(
+            wallet
+                .deposits(&[&unlisted])
+                .await
+                .unwrap()
+                .iter()
+                .filter(|deposit| deposit.is_confirmed(&Config::default()))
+                .map(|deposit| deposit.amount)
+                .sum::<Amount>(),
+            Amount::from_sat(900_000_000)
+        );
+    }
+}
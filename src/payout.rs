@@ -0,0 +1,974 @@
+use super::{
+    amount::Amount,
+    backend::{Deposit, PaymentBackend},
+    config::Config,
+    ledger,
+    store::{self, Record, Table},
+    sweep::SweepPlan,
+    user::User,
+};
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, TimeZone, Utc, Weekday};
+use rand::random;
+use sled::Db;
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+/// The key the scheduler's state is kept under in the payouts table.
+const STATE_KEY: &'static str = "state";
+
+/// The key the sweeping process' mark is kept under in the sweep lock table.
+const SWEEP_LOCK_KEY: &'static str = "sweeping";
+
+/// This process' mark on the sweep lock, chosen at random the first time it is needed.
+static PROCESS_MARK: AtomicU64 = AtomicU64::new(0);
+
+/// How often the scheduler wakes up to re-check the clock or the sweepable balance.
+const POLL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// When the store's deposits are swept into its wallet.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Schedule {
+    /// At midnight UTC at the start of the given day of every month (or its last day, if None)
+    Monthly(Option<u32>),
+
+    /// At midnight UTC at the start of the given day of every week
+    Weekly(Weekday),
+
+    /// Whenever the sweepable balance reaches the given amount
+    Threshold(Amount),
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Parses schedules of the form "monthly", "monthly:15", "weekly:sun" or "threshold:0.01".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+
+        match (parts.next().unwrap_or_default(), parts.next()) {
+            ("monthly", None) | ("monthly", Some("last")) => Ok(Self::Monthly(None)),
+            ("monthly", Some(day)) => match day.parse() {
+                Ok(day) if (1..=28).contains(&day) => Ok(Self::Monthly(Some(day))),
+                _ => Err(format!(
+                    "invalid day of month {:?} (expected 1-28 or \"last\")",
+                    day
+                )),
+            },
+            ("weekly", Some(day)) => day
+                .parse()
+                .map(Self::Weekly)
+                .map_err(|_| format!("invalid weekday {:?}", day)),
+            ("threshold", Some(amount)) => Amount::from_btc_str(amount).map(Self::Threshold),
+            _ => Err(format!(
+                "invalid schedule {:?} (expected monthly[:day], weekly:day or threshold:amount)",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Monthly(None) => write!(f, "monthly:last"),
+            Self::Monthly(Some(day)) => write!(f, "monthly:{}", day),
+            Self::Weekly(day) => write!(f, "weekly:{}", day),
+            Self::Threshold(amount) => write!(f, "threshold:{}", amount),
+        }
+    }
+}
+
+impl Schedule {
+    /// The scheduled run on the given date, if any.
+    fn run_on(&self, date: NaiveDate) -> Option<DateTime<Utc>> {
+        let scheduled = match self {
+            Self::Monthly(Some(day)) => date.day() == *day,
+            Self::Monthly(None) => date.succ().month() != date.month(),
+            Self::Weekly(day) => date.weekday() == *day,
+            Self::Threshold(_) => false,
+        };
+
+        if scheduled {
+            Some(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+        } else {
+            None
+        }
+    }
+
+    /// The most recent scheduled run at or before the given moment. None for threshold schedules.
+    pub fn last_run(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if let Self::Threshold(_) = self {
+            return None;
+        }
+
+        // Every schedule runs at least once every 31 days
+        (0..=31)
+            .map(|days_ago| now.date().naive_utc() - ChronoDuration::days(days_ago))
+            .find_map(|date| self.run_on(date))
+    }
+
+    /// The next scheduled run strictly after the given moment. None for threshold schedules.
+    pub fn next_run(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if let Self::Threshold(_) = self {
+            return None;
+        }
+
+        (1..=32)
+            .map(|days_ahead| now.date().naive_utc() + ChronoDuration::days(days_ahead))
+            .find_map(|date| self.run_on(date))
+    }
+
+    /// Identifies the period a scheduled run belongs to, so it is swept at most once.
+    pub fn period(&self, run: DateTime<Utc>) -> Option<String> {
+        match self {
+            Self::Monthly(_) => Some(run.format("%Y-%m").to_string()),
+            Self::Weekly(_) => Some(format!(
+                "{}-W{:02}",
+                run.iso_week().year(),
+                run.iso_week().week()
+            )),
+            Self::Threshold(_) => None,
+        }
+    }
+}
+
+/// The scheduler's persisted progress.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SchedulerState {
+    pub last_run: Option<SystemTime>,
+
+    /// The last period that was swept successfully
+    pub last_period: Option<String>,
+    pub next_run: Option<SystemTime>,
+}
+
+impl Record for SchedulerState {
+    const VERSION: u32 = 1;
+}
+
+/// The result of a single payout run.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PayoutOutcome {
+    Swept {
+        txids: Vec<String>,
+        value: Amount,
+        fee: Amount,
+        dust: usize,
+    },
+    NothingToSweep,
+    Failed(String),
+
+    /// Some of the sweep's transactions were broadcast before one of them failed
+    PartiallySwept {
+        txids: Vec<String>,
+        error: String,
+    },
+}
+
+/// A record of a single payout run.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PayoutRun {
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    pub period: Option<String>,
+    pub outcome: PayoutOutcome,
+}
+
+impl Record for PayoutRun {
+    const VERSION: u32 = 1;
+}
+
+/// An input of a previewed sweep transaction.
+#[derive(Serialize, Debug)]
+pub struct PreviewInput {
+    pub outpoint: String,
+    pub address: Option<String>,
+
+    /// The user the deposit belongs to
+    pub username: Option<String>,
+    pub amount: String,
+}
+
+/// A previewed sweep transaction, built but neither signed nor broadcast.
+#[derive(Serialize, Debug)]
+pub struct PreviewTx {
+    pub inputs: Vec<PreviewInput>,
+    pub total: String,
+    pub fee: String,
+    pub value: String,
+    pub vsize: u64,
+
+    /// The unsigned transaction, for inspection with decoderawtransaction
+    pub hex: String,
+}
+
+/// Everything a sweep would do right now, for an admin to review before executing it.
+#[derive(Serialize, Debug)]
+pub struct SweepPreview {
+    pub destination: String,
+    pub fee_rate: u64,
+
+    /// Every amount is a decimal amount of this currency
+    pub currency: &'static str,
+    pub total: String,
+    pub fee: String,
+    pub value: String,
+    pub txs: Vec<PreviewTx>,
+
+    /// Outpoints left behind because they are worth less than the fee to spend them
+    pub dust: Vec<String>,
+
+    /// Must be given back to execute this sweep, which only happens if nothing has changed
+    pub confirmation: String,
+}
+
+impl fmt::Display for SweepPreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Sweep to {} at {} sat/vB",
+            self.destination, self.fee_rate
+        )?;
+
+        for (i, tx) in self.txs.iter().enumerate() {
+            writeln!(f, "\nTransaction {} ({} vB):", i + 1, tx.vsize)?;
+
+            for input in &tx.inputs {
+                writeln!(
+                    f,
+                    "  {} {} ({}) {} {}",
+                    input.outpoint,
+                    input.address.as_deref().unwrap_or("-"),
+                    input.username.as_deref().unwrap_or("unknown user"),
+                    input.amount,
+                    self.currency
+                )?;
+            }
+
+            writeln!(
+                f,
+                "  total {} {cur}, fee {} {cur}, value {} {cur}",
+                tx.total,
+                tx.fee,
+                tx.value,
+                cur = self.currency
+            )?;
+            writeln!(f, "  unsigned hex {}", tx.hex)?;
+        }
+
+        if !self.dust.is_empty() {
+            writeln!(f, "\nLeft behind as dust: {}", self.dust.join(", "))?;
+        }
+
+        writeln!(
+            f,
+            "\nTotal {} {cur}, fee {} {cur}, {} receives {} {cur}",
+            self.total,
+            self.fee,
+            self.destination,
+            self.value,
+            cur = self.currency
+        )?;
+        write!(f, "Confirmation: {}", self.confirmation)
+    }
+}
+
+/// The result of a manually triggered sweep.
+#[derive(Debug)]
+pub enum ManualRun {
+    Ran(PayoutRun),
+
+    /// The sweep no longer matches the confirmed preview, so nothing was broadcast
+    Changed(SweepPreview),
+
+    /// Another sweep is running, so nothing was done
+    Busy,
+}
+
+fn process_mark() -> u64 {
+    // Never zero, which means unchosen
+    let _ =
+        PROCESS_MARK.compare_exchange(0, random::<u64>() | 1, Ordering::AcqRel, Ordering::Acquire);
+
+    PROCESS_MARK.load(Ordering::Acquire)
+}
+
+/// Held while a sweep is planned and broadcast, so that the scheduler and manual sweeps never
+/// spend the same deposits at once. Released when dropped.
+struct SweepLock {
+    table: Table<u64>,
+}
+
+impl SweepLock {
+    /// Takes the lock, or returns None if this process is already sweeping. Only one process can
+    /// open the database at a time, so a lock marked by any other process was left by one that
+    /// stopped mid-sweep, and is taken over.
+    fn acquire(db: &Db) -> Result<Option<Self>, String> {
+        let (table, mark) = (store::sweep_lock(db)?, process_mark());
+
+        loop {
+            let held = table.get(SWEEP_LOCK_KEY)?;
+            if held == Some(mark) {
+                return Ok(None);
+            }
+
+            if table
+                .compare_and_swap(SWEEP_LOCK_KEY, held.as_ref(), Some(&mark))?
+                .is_ok()
+            {
+                return Ok(Some(Self { table }));
+            }
+        }
+    }
+}
+
+impl Drop for SweepLock {
+    fn drop(&mut self) {
+        if let Err(e) = self
+            .table
+            .compare_and_swap(SWEEP_LOCK_KEY, Some(&process_mark()), None)
+        {
+            eprintln!("could not release the sweep lock: {}", e);
+        }
+    }
+}
+
+/// Sweeps the store's deposits into its wallet on a schedule, surviving restarts.
+pub struct Scheduler<'a> {
+    db: Db,
+    backend: &'a dyn PaymentBackend,
+    cfg: &'a Config,
+}
+
+impl<'a> Scheduler<'a> {
+    pub fn new(db: Db, backend: &'a dyn PaymentBackend, cfg: &'a Config) -> Self {
+        Self { db, backend, cfg }
+    }
+
+    /// Loads the scheduler's state, if it has ever run.
+    pub fn load_state(&self) -> Result<Option<SchedulerState>, String> {
+        store::payouts(&self.db)?.get(STATE_KEY)
+    }
+
+    fn commit_state(&self, state: &SchedulerState) -> Result<(), String> {
+        store::payouts(&self.db)?.insert(STATE_KEY, state)
+    }
+
+    /// Gets every run recorded, oldest first.
+    pub fn runs(&self) -> Result<Vec<PayoutRun>, String> {
+        store::payout_runs(&self.db)?.values().collect()
+    }
+
+    /// Records the run in the history. A run that found nothing to sweep replaces the last run
+    /// if that found nothing either, so that threshold polls don't fill the history.
+    fn record_run(&self, run: &PayoutRun) -> Result<(), String> {
+        let started_ms = run
+            .started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_millis() as u64;
+        let runs = store::payout_runs(&self.db)?;
+
+        if let PayoutOutcome::NothingToSweep = run.outcome {
+            if let Some((last_key, last)) = runs.last()? {
+                if let PayoutOutcome::NothingToSweep = last.outcome {
+                    runs.remove(last_key)?;
+                }
+            }
+        }
+
+        runs.insert(started_ms.to_be_bytes(), run)
+    }
+
+    /// Credits outstanding deposits, then plans a sweep of everything sweepable.
+    async fn plan(&self) -> Result<SweepPlan, String> {
+        // Credit users for their deposits before the outputs disappear in the sweep
+        ledger::sync_all(&self.db, self.backend, self.cfg).await?;
+
+        self.plan_sweep().await
+    }
+
+    /// Plans a sweep of every sweepable deposit that has already been credited, without
+    /// crediting anything new.
+    async fn plan_sweep(&self) -> Result<SweepPlan, String> {
+        let addrs = self.deposit_addresses()?;
+        let mut credited = HashSet::new();
+        for sub in store::ledger(&self.db)?.values() {
+            credited.extend(sub?.credited);
+        }
+
+        self.backend
+            .plan_sweep(
+                &addrs.iter().map(String::as_str).collect::<Vec<_>>(),
+                &credited,
+                &self.cfg.wallet_address,
+            )
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Gets every deposit address handed out to a user. Nothing else the wallet holds is ever
+    /// swept.
+    fn deposit_addresses(&self) -> Result<Vec<String>, String> {
+        Ok(User::deposit_addresses(&self.db)?
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .collect())
+    }
+
+    /// Drafts the planned transactions for review, without signing or broadcasting them.
+    async fn preview_plan(&self, plan: SweepPlan) -> Result<SweepPreview, String> {
+        let hexes = self
+            .backend
+            .draft_sweep(&plan)
+            .await
+            .map_err(|e| e.to_string())?;
+        let owners: HashMap<String, String> =
+            User::deposit_addresses(&self.db)?.into_iter().collect();
+        let backend = self.cfg.payment_backend;
+
+        Ok(SweepPreview {
+            destination: plan.destination.clone(),
+            fee_rate: plan.fee_rate,
+            currency: backend.currency(),
+            total: backend.format_amount(plan.total()),
+            fee: backend.format_amount(plan.fee()),
+            value: backend.format_amount(plan.value()),
+            confirmation: plan.confirmation(),
+            dust: plan.dust.iter().map(Deposit::id).collect(),
+            txs: plan
+                .txs
+                .into_iter()
+                .zip(hexes)
+                .map(|(tx, hex)| PreviewTx {
+                    inputs: tx
+                        .inputs
+                        .iter()
+                        .map(|deposit| PreviewInput {
+                            outpoint: deposit.id(),
+                            address: deposit.address.clone(),
+                            username: deposit
+                                .address
+                                .as_ref()
+                                .and_then(|addr| owners.get(addr))
+                                .cloned(),
+                            amount: backend.format_amount(deposit.amount),
+                        })
+                        .collect(),
+                    total: backend.format_amount(tx.total),
+                    fee: backend.format_amount(tx.fee),
+                    value: backend.format_amount(tx.value),
+                    vsize: tx.vsize,
+                    hex,
+                })
+                .collect(),
+        })
+    }
+
+    /// Shows what a sweep would do right now, without broadcasting anything or writing to the
+    /// database. Only deposits that are already credited are previewed, so every input of a
+    /// confirmed preview has been credited; deposits credited when the sweep is executed change
+    /// it instead.
+    pub async fn preview(&self) -> Result<SweepPreview, String> {
+        let plan = self.plan_sweep().await?;
+
+        self.preview_plan(plan).await
+    }
+
+    /// Sweeps everything sweepable on demand, but only if the sweep still matches the preview
+    /// the confirmation was taken from. The run is recorded in the history without a period,
+    /// so it doesn't stand in for the next scheduled run.
+    pub async fn execute(&self, confirmation: &str) -> Result<ManualRun, String> {
+        let _lock = match SweepLock::acquire(&self.db)? {
+            Some(lock) => lock,
+            None => return Ok(ManualRun::Busy),
+        };
+        let plan = self.plan().await?;
+
+        if plan.confirmation() != confirmation {
+            return self.preview_plan(plan).await.map(ManualRun::Changed);
+        }
+
+        let run = self.run_plan(Ok(plan), None).await?;
+        println!("manual payout: {:?}", run.outcome);
+
+        Ok(ManualRun::Ran(run))
+    }
+
+    /// Sweeps everything sweepable once, recording the outcome.
+    async fn run_once(&self, period: Option<String>) -> Result<PayoutRun, String> {
+        let plan = self.plan().await;
+
+        self.run_plan(plan, period).await
+    }
+
+    /// Executes the plan, recording the outcome.
+    async fn run_plan(
+        &self,
+        plan: Result<SweepPlan, String>,
+        period: Option<String>,
+    ) -> Result<PayoutRun, String> {
+        let started_at = SystemTime::now();
+
+        let outcome = match plan {
+            Ok(plan) if plan.txs.is_empty() => PayoutOutcome::NothingToSweep,
+            Ok(plan) => match self.backend.execute_sweep(&plan).await {
+                Ok(txids) => PayoutOutcome::Swept {
+                    txids,
+                    value: plan.value(),
+                    fee: plan.fee(),
+                    dust: plan.dust.len(),
+                },
+                Err(e) if e.broadcast.is_empty() => PayoutOutcome::Failed(e.error.to_string()),
+                Err(e) => PayoutOutcome::PartiallySwept {
+                    txids: e.broadcast,
+                    error: e.error.to_string(),
+                },
+            },
+            Err(e) => PayoutOutcome::Failed(e),
+        };
+
+        let run = PayoutRun {
+            started_at,
+            finished_at: SystemTime::now(),
+            period,
+            outcome,
+        };
+        self.record_run(&run)?;
+
+        Ok(run)
+    }
+
+    /// Determines whether or not the threshold schedule's balance has been reached.
+    async fn threshold_reached(&self, threshold: Amount) -> Result<bool, String> {
+        let addrs = self.deposit_addresses()?;
+        let deposits = self
+            .backend
+            .deposits(&addrs.iter().map(String::as_str).collect::<Vec<_>>())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // Only deposits deep enough to be swept count towards the threshold
+        Ok(deposits
+            .iter()
+            .filter(|deposit| deposit.confirmations >= u64::from(self.cfg.min_confirmations))
+            .map(|deposit| deposit.amount)
+            .sum::<Amount>()
+            >= self.cfg.amount(threshold))
+    }
+
+    /// Runs a sweep if one is due, returning how long to wait before checking again.
+    pub async fn tick(&self, now: DateTime<Utc>) -> Result<Duration, String> {
+        let schedule = self.cfg.payout_schedule;
+        let mut state = match self.load_state()? {
+            Some(state) => state,
+
+            // Don't sweep a period that began before the scheduler was ever started
+            None => SchedulerState {
+                last_period: schedule.last_run(now).and_then(|run| schedule.period(run)),
+                ..SchedulerState::default()
+            },
+        };
+
+        // Monthly and weekly sweeps catch up on the most recent missed period only, and never
+        // repeat a period that has already been swept
+        let due = match schedule {
+            Schedule::Threshold(threshold) => self.threshold_reached(threshold).await?,
+            _ => {
+                let period = schedule.last_run(now).and_then(|run| schedule.period(run));
+                period.is_some() && period != state.last_period
+            }
+        };
+
+        // A due run that finds a manual sweep in progress is retried on the next tick
+        let lock = if due {
+            SweepLock::acquire(&self.db)?
+        } else {
+            None
+        };
+
+        if let Some(_lock) = lock {
+            let period = schedule.last_run(now).and_then(|run| schedule.period(run));
+            let run = self.run_once(period.clone()).await?;
+            println!("payout for {:?}: {:?}", period, run.outcome);
+
+            state.last_run = Some(run.started_at);
+
+            // Failed runs are retried on the next tick, which sweeps whatever a partial run left
+            if !matches!(
+                run.outcome,
+                PayoutOutcome::Failed(_) | PayoutOutcome::PartiallySwept { .. }
+            ) {
+                state.last_period = period;
+            }
+        }
+
+        state.next_run = schedule.next_run(now).map(SystemTime::from);
+        self.commit_state(&state)?;
+
+        // Never oversleep the next run, but never sleep a negative duration either
+        Ok(schedule
+            .next_run(now)
+            .and_then(|next| (next - now).to_std().ok())
+            .map_or(POLL_INTERVAL, |until_next| until_next.min(POLL_INTERVAL)))
+    }
+
+    /// Runs the scheduler forever.
+    pub async fn run(self) {
+        loop {
+            let wait = match self.tick(Utc::now()).await {
+                Ok(wait) => wait,
+                Err(e) => {
+                    eprintln!("payout scheduler error: {}", e);
+
+                    POLL_INTERVAL
+                }
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        backend::Bitcoind, ingress::RpcConnection, ledger::Subscription, mock_rpc::MockBitcoind,
+    };
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.ymd(y, m, d).and_hms(h, 0, 0)
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!("monthly".parse(), Ok(Schedule::Monthly(None)));
+        assert_eq!("monthly:last".parse(), Ok(Schedule::Monthly(None)));
+        assert_eq!("monthly:15".parse(), Ok(Schedule::Monthly(Some(15))));
+        assert_eq!("weekly:sun".parse(), Ok(Schedule::Weekly(Weekday::Sun)));
+        assert_eq!(
+            "threshold:0.01".parse(),
+            Ok(Schedule::Threshold(Amount::from_sat(1_000_000)))
+        );
+
+        assert!("monthly:31".parse::<Schedule>().is_err());
+        assert!("weekly".parse::<Schedule>().is_err());
+        assert!("daily".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_last_day_of_month() {
+        let schedule = Schedule::Monthly(None);
+
+        // February isn't always 28 days long
+        assert_eq!(
+            schedule.next_run(at(2024, 2, 10, 12)),
+            Some(at(2024, 2, 29, 0))
+        );
+        assert_eq!(
+            schedule.next_run(at(2023, 2, 10, 12)),
+            Some(at(2023, 2, 28, 0))
+        );
+
+        // Past this month's run, so the next is next month's
+        assert_eq!(
+            schedule.next_run(at(2026, 10, 31, 1)),
+            Some(at(2026, 11, 30, 0))
+        );
+        assert_eq!(
+            schedule.last_run(at(2026, 10, 31, 1)),
+            Some(at(2026, 10, 31, 0))
+        );
+        assert_eq!(
+            schedule.last_run(at(2026, 10, 17, 0)),
+            Some(at(2026, 9, 30, 0))
+        );
+    }
+
+    #[test]
+    fn test_periods() {
+        let monthly = Schedule::Monthly(Some(1));
+        assert_eq!(
+            monthly.period(at(2026, 10, 1, 0)),
+            Some("2026-10".to_owned())
+        );
+
+        let weekly = Schedule::Weekly(Weekday::Mon);
+        let run = weekly.last_run(at(2026, 10, 17, 9)).unwrap();
+        assert_eq!(run, at(2026, 10, 12, 0));
+        assert_eq!(weekly.period(run), Some("2026-W42".to_owned()));
+
+        assert_eq!(
+            Schedule::Threshold(Amount::ZERO).next_run(at(2026, 10, 17, 9)),
+            None
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_first_tick_does_not_sweep() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config::default();
+        let backend = Bitcoind::new(RpcConnection::new("http://127.0.0.1:1/"), &cfg);
+        let scheduler = Scheduler::new(db, &backend, &cfg);
+
+        // No sweep is due yet, so the node is never contacted
+        let now = at(2026, 10, 17, 9);
+        let wait = scheduler.tick(now).await.unwrap();
+        assert_eq!(wait, POLL_INTERVAL);
+
+        let state = scheduler.load_state().unwrap().unwrap();
+        assert_eq!(state.last_period, Some("2026-09".to_owned()));
+        assert_eq!(state.next_run, Some(SystemTime::from(at(2026, 10, 31, 0))));
+        assert!(scheduler.runs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_idle_runs_collapse() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config::default();
+        let backend = Bitcoind::new(RpcConnection::new("http://127.0.0.1:1/"), &cfg);
+        let scheduler = Scheduler::new(db, &backend, &cfg);
+        let run = |secs, outcome| PayoutRun {
+            started_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            finished_at: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            period: None,
+            outcome,
+        };
+
+        scheduler
+            .record_run(&run(1, PayoutOutcome::NothingToSweep))
+            .unwrap();
+        scheduler
+            .record_run(&run(2, PayoutOutcome::NothingToSweep))
+            .unwrap();
+        let runs = scheduler.runs().unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(
+            runs[0].started_at,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2)
+        );
+
+        // Runs that did something are always kept
+        scheduler
+            .record_run(&run(3, PayoutOutcome::Failed("node down".to_owned())))
+            .unwrap();
+        scheduler
+            .record_run(&run(4, PayoutOutcome::NothingToSweep))
+            .unwrap();
+        assert_eq!(scheduler.runs().unwrap().len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_sweep_lock() {
+        let mut db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            wallet_address: "bcrt1qstore".to_owned(),
+            ..Config::default()
+        };
+        let (node, _) = store_with_deposit(&mut db, &cfg).await;
+        let backend = node.backend();
+        let scheduler = Scheduler::new(db, &backend, &cfg);
+
+        // Previewing credits nothing, so nothing is sweepable yet
+        assert!(scheduler.preview().await.unwrap().txs.is_empty());
+        assert!(!Subscription::load(&scheduler.db, "lol")
+            .unwrap()
+            .is_active());
+
+        // Previewing leaves the lock alone
+        ledger::sync_all(&scheduler.db, &backend, &cfg)
+            .await
+            .unwrap();
+        let preview = scheduler.preview().await.unwrap();
+        assert_eq!(preview.txs.len(), 1);
+
+        let lock = SweepLock::acquire(&scheduler.db).unwrap().unwrap();
+        assert!(SweepLock::acquire(&scheduler.db).unwrap().is_none());
+        assert!(matches!(
+            scheduler.execute(&preview.confirmation).await.unwrap(),
+            ManualRun::Busy
+        ));
+        assert!(node.wallet().sent.is_empty());
+        drop(lock);
+
+        // A lock left by a process that stopped mid-sweep is taken over
+        let sweep_lock = store::sweep_lock(&scheduler.db).unwrap();
+        sweep_lock.insert(SWEEP_LOCK_KEY, &1).unwrap();
+        assert!(matches!(
+            scheduler.execute(&preview.confirmation).await.unwrap(),
+            ManualRun::Ran(_)
+        ));
+        assert_eq!(node.wallet().sent.len(), 1);
+        assert_eq!(sweep_lock.get(SWEEP_LOCK_KEY).unwrap(), None);
+        assert!(Subscription::load(&scheduler.db, "lol")
+            .unwrap()
+            .is_active());
+    }
+
+    /// Starts a mock node holding one deposit to an indexed user address.
+    async fn store_with_deposit(db: &mut Db, cfg: &Config) -> (MockBitcoind, String) {
+        let node = MockBitcoind::start();
+        let mut u = User::new("lol".to_owned(), "hunter2".to_owned()).unwrap();
+        let addr = u
+            .generate_new_acc_address(&node.backend(), cfg)
+            .await
+            .unwrap();
+        assert!(u.register(db).unwrap());
+        node.wallet().deposit(&addr, Amount::from_sat(50_000), 6);
+
+        (node, addr)
+    }
+
+    #[actix_rt::test]
+    async fn test_missed_run_catches_up() {
+        let mut db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            wallet_address: "bcrt1qstore".to_owned(),
+            ..Config::default()
+        };
+        let (node, _) = store_with_deposit(&mut db, &cfg).await;
+        let backend = node.backend();
+        let scheduler = Scheduler::new(db, &backend, &cfg);
+
+        // The store was down over September's run
+        scheduler
+            .commit_state(&SchedulerState {
+                last_period: Some("2026-08".to_owned()),
+                ..SchedulerState::default()
+            })
+            .unwrap();
+
+        scheduler.tick(at(2026, 10, 17, 9)).await.unwrap();
+        let runs = scheduler.runs().unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].period, Some("2026-09".to_owned()));
+        assert!(matches!(runs[0].outcome, PayoutOutcome::Swept { .. }));
+        assert_eq!(node.wallet().sent.len(), 1);
+
+        // September is never swept twice
+        scheduler.tick(at(2026, 10, 17, 10)).await.unwrap();
+        assert_eq!(scheduler.runs().unwrap().len(), 1);
+        assert_eq!(
+            scheduler.load_state().unwrap().unwrap().last_period,
+            Some("2026-09".to_owned())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_partial_run_is_recorded() {
+        let mut db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            wallet_address: "bcrt1qstore".to_owned(),
+            ..Config::default()
+        };
+        let (node, _) = store_with_deposit(&mut db, &cfg).await;
+        let backend = node.backend();
+        let scheduler = Scheduler::new(db, &backend, &cfg);
+
+        // The same deposit planned twice can only be spent once
+        let mut plan = scheduler.plan().await.unwrap();
+        plan.txs.extend(scheduler.plan().await.unwrap().txs);
+        let run = scheduler
+            .run_plan(Ok(plan), Some("2026-09".to_owned()))
+            .await
+            .unwrap();
+
+        let sent = node.wallet().sent[0].0.clone();
+        match &scheduler.runs().unwrap()[0].outcome {
+            PayoutOutcome::PartiallySwept { txids, .. } => assert_eq!(txids, &vec![sent]),
+            outcome => panic!("recorded {:?} for a partial sweep", outcome),
+        }
+        assert!(matches!(run.outcome, PayoutOutcome::PartiallySwept { .. }));
+    }
+
+    #[actix_rt::test]
+    async fn test_manual_run_requires_current_confirmation() {
+        let mut db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            wallet_address: "bcrt1qstore".to_owned(),
+            ..Config::default()
+        };
+        let (node, addr) = store_with_deposit(&mut db, &cfg).await;
+        let backend = node.backend();
+        let scheduler = Scheduler::new(db, &backend, &cfg);
+        ledger::sync_all(&scheduler.db, &backend, &cfg)
+            .await
+            .unwrap();
+
+        let preview = scheduler.preview().await.unwrap();
+        assert_eq!(preview.txs.len(), 1);
+        assert_eq!(preview.txs[0].inputs[0].username, Some("lol".to_owned()));
+        assert!(node.wallet().sent.is_empty());
+
+        // A deposit arrived after the preview, so it no longer describes the sweep
+        node.wallet().deposit(&addr, Amount::from_sat(30_000), 6);
+        let preview = match scheduler.execute(&preview.confirmation).await.unwrap() {
+            ManualRun::Changed(preview) => preview,
+            ManualRun::Ran(run) => panic!("swept an unconfirmed plan: {:?}", run),
+            ManualRun::Busy => panic!("no other sweep is running"),
+        };
+        assert!(node.wallet().sent.is_empty());
+
+        match scheduler.execute(&preview.confirmation).await.unwrap() {
+            ManualRun::Ran(run) => assert_eq!(run.period, None),
+            ManualRun::Changed(_) => panic!("the sweep changed without a new deposit"),
+            ManualRun::Busy => panic!("no other sweep is running"),
+        }
+        assert_eq!(node.wallet().sent.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_deposit_confirmed_after_sync_is_not_swept() {
+        let mut db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            wallet_address: "bcrt1qstore".to_owned(),
+            ..Config::default()
+        };
+        let (node, addr) = store_with_deposit(&mut db, &cfg).await;
+        let backend = node.backend();
+        let scheduler = Scheduler::new(db, &backend, &cfg);
+        let late = node.wallet().deposit(&addr, Amount::from_sat(30_000), 0);
+
+        // The deposit confirms between crediting and planning
+        ledger::sync_all(&scheduler.db, &backend, &cfg)
+            .await
+            .unwrap();
+        for utxo in &mut node.wallet().utxos {
+            utxo.confirmations = 6;
+        }
+        let plan = scheduler.plan_sweep().await.unwrap();
+        assert_eq!(plan.total(), Amount::from_sat(50_000));
+        assert!(plan
+            .txs
+            .iter()
+            .flat_map(|tx| &tx.inputs)
+            .all(|deposit| deposit.txid != late));
+
+        // It is swept once the next run has credited it
+        let plan = scheduler.plan().await.unwrap();
+        assert_eq!(plan.total(), Amount::from_sat(80_000));
+        assert!(Subscription::load(&scheduler.db, "lol")
+            .unwrap()
+            .credited
+            .contains(&format!("{}:0", late)));
+    }
+}
@@ -0,0 +1,44 @@
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::QrCode;
+
+/// The smallest width and height of a rendered QR code, in pixels.
+const QR_MIN_SIZE: u32 = 256;
+
+/// Renders the text as a QR code PNG.
+pub fn png(text: &str) -> Result<Vec<u8>, String> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| e.to_string())?;
+    let img = code
+        .render::<Luma<u8>>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .build();
+
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(img)
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .map_err(|e| e.to_string())?;
+
+    Ok(png)
+}
+
+/// Renders the text as a QR code PNG, returned as a data: URI so that it can be shown in an <img>
+/// without JavaScript or another request.
+pub fn png_data_uri(text: &str) -> Result<String, String> {
+    png(text).map(|png| format!("data:image/png;base64,{}", base64::encode(&png)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::GenericImageView;
+
+    #[test]
+    fn test_png_data_uri() {
+        let uri = png_data_uri("LIGHTNING:LNBCRT200U1MOCK1").unwrap();
+        let png = base64::decode(uri.strip_prefix("data:image/png;base64,").unwrap()).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        let img = image::load_from_memory(&png).unwrap();
+        assert!(img.width() >= QR_MIN_SIZE);
+    }
+}
@@ -0,0 +1,320 @@
+use super::{
+    store::{self, Record, Text},
+    user::User,
+    watcher,
+};
+
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::Payload,
+    error, web, Error as ActixError, FromRequest, HttpRequest,
+};
+use futures::future::{ready, Ready};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sled::{Db, Transactional};
+use tokio::time;
+
+use std::time::{Duration, SystemTime};
+
+/// The name of the cookie carrying the user's session token.
+pub const SESSION_COOKIE: &'static str = "session";
+
+/// Sessions are valid for one week after they are issued.
+const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How often expired sessions are forgotten.
+const REAP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The number of alphanumeric characters in a session or CSRF token.
+const TOKEN_LEN: usize = 48;
+
+/// Generates a random alphanumeric token.
+fn random_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// A logged in user's session, stored server-side.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub username: String,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+impl Session {
+    /// Issues a new session for the user, returning its random token.
+    pub fn create(db: &Db, username: &str) -> Result<String, String> {
+        let token = random_token();
+        let now = SystemTime::now();
+
+        store::sessions(db)?
+            .insert(
+                &token,
+                &Session {
+                    username: username.to_owned(),
+                    created_at: now,
+                    expires_at: now + SESSION_TTL,
+                },
+            )
+            .map(|_| token)
+    }
+
+    /// Looks up the session for the token, discarding it if it has expired.
+    pub fn load(db: &Db, token: &str) -> Result<Option<Self>, String> {
+        let sess = match store::sessions(db)?.get(token)? {
+            Some(sess) => sess,
+            None => return Ok(None),
+        };
+
+        if sess.expires_at <= SystemTime::now() {
+            Self::revoke(db, token)?;
+
+            return Ok(None);
+        }
+
+        Ok(Some(sess))
+    }
+
+    /// Gets the token that forms posted with the session must carry, issuing it the first time
+    /// it is asked for.
+    pub fn csrf_token(db: &Db, token: &str) -> Result<String, String> {
+        let csrf = Text(random_token());
+
+        // Concurrent requests all end up with whichever token was stored first
+        match store::csrf_tokens(db)?.compare_and_swap(token, None, Some(&csrf))? {
+            Ok(()) => Ok(csrf.0),
+            Err(current) => current
+                .map(|current| current.0)
+                .ok_or("the CSRF token vanished".to_owned()),
+        }
+    }
+
+    /// Invalidates a single session, along with its CSRF token.
+    pub fn revoke(db: &Db, token: &str) -> Result<(), String> {
+        let (sessions, csrf_tokens) = (store::sessions(db)?, store::csrf_tokens(db)?);
+
+        store::committed((sessions.tree(), csrf_tokens.tree()).transaction(
+            |(tx_sessions, tx_csrf_tokens)| {
+                csrf_tokens.within(tx_csrf_tokens).remove(token)?;
+                sessions.within(tx_sessions).remove(token)
+            },
+        ))
+    }
+
+    /// Invalidates every session belonging to the user.
+    pub fn revoke_all(db: &Db, username: &str) -> Result<(), String> {
+        for entry in store::sessions(db)?.iter() {
+            let (token, sess) = entry?;
+
+            if sess.username == username {
+                Self::revoke(db, &token)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets every session that expired before now, along with its CSRF token, and any CSRF
+    /// token left without a session. Returns the number of sessions forgotten.
+    pub fn reap(db: &Db, now: SystemTime) -> Result<u64, String> {
+        let (sessions, csrf_tokens) = (store::sessions(db)?, store::csrf_tokens(db)?);
+        let mut reaped = 0;
+
+        for entry in sessions.iter() {
+            let (token, sess) = entry?;
+
+            if sess.expires_at <= now {
+                Self::revoke(db, &token)?;
+                reaped += 1;
+            }
+        }
+
+        // A token asked for just as its session was revoked outlives it
+        for entry in csrf_tokens.iter() {
+            let (token, _) = entry?;
+
+            if sessions.get(&token)?.is_none() {
+                csrf_tokens.remove(&token)?;
+            }
+        }
+
+        Ok(reaped)
+    }
+
+    /// Builds the cookie handed to the browser. Only the token ever leaves the server.
+    pub fn cookie(token: String) -> Cookie<'static> {
+        Cookie::build(SESSION_COOKIE, token)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .finish()
+    }
+
+    /// Builds a cookie instructing the browser to forget its session token.
+    pub fn removal_cookie() -> Cookie<'static> {
+        let mut cookie = Self::cookie(String::new());
+        cookie.make_removal();
+
+        cookie
+    }
+}
+
+impl Record for Session {
+    const VERSION: u32 = 1;
+}
+
+/// Forgets sessions as they expire, whether or not they are ever used again, so that logins
+/// don't grow the database without bound.
+pub struct Reaper {
+    db: Db,
+}
+
+impl Reaper {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    /// Forgets expired sessions every REAP_INTERVAL. Runs forever.
+    pub async fn run(self) {
+        loop {
+            if let Err(e) = Session::reap(&self.db, SystemTime::now()) {
+                eprintln!("session reaper error: {}", e);
+            }
+
+            time::sleep(REAP_INTERVAL).await;
+        }
+    }
+}
+
+/// Finds the session cookie set by a response, for tests acting as a browser.
+#[cfg(test)]
+pub fn cookie_from<B>(resp: &actix_web::dev::ServiceResponse<B>) -> Option<Cookie<'static>> {
+    resp.response()
+        .cookies()
+        .find(|cookie| cookie.name() == SESSION_COOKIE)
+        .map(Cookie::into_owned)
+}
+
+/// A request made by a user holding a valid session.
+pub struct Authenticated {
+    pub token: String,
+    pub user: User,
+}
+
+impl Authenticated {
+    /// Rejects forms that weren't posted from one of the store's own pages, by checking that
+    /// they carry the session's CSRF token.
+    pub fn check_csrf(&self, db: &Db, csrf_token: &str) -> Result<(), ActixError> {
+        let expected =
+            Session::csrf_token(db, &self.token).map_err(|e| error::ErrorInternalServerError(e))?;
+
+        if !watcher::secrets_match(csrf_token.as_bytes(), expected.as_bytes()) {
+            return Err(error::ErrorForbidden("invalid CSRF token"));
+        }
+
+        Ok(())
+    }
+
+    fn from_request_sync(req: &HttpRequest) -> Result<Self, ActixError> {
+        let db = req
+            .app_data::<web::Data<Db>>()
+            .ok_or(error::ErrorInternalServerError("no database"))?;
+
+        let token = req
+            .cookie(SESSION_COOKIE)
+            .ok_or(error::ErrorUnauthorized("not logged in"))?
+            .value()
+            .to_owned();
+
+        let sess = Session::load(db, &token)
+            .map_err(|e| error::ErrorInternalServerError(e))?
+            .ok_or(error::ErrorUnauthorized("session expired"))?;
+
+        let user = User::load(db, &sess.username)
+            .map_err(|e| error::ErrorInternalServerError(e))?
+            .ok_or(error::ErrorUnauthorized("user doesn't exist"))?;
+
+        Ok(Self { token, user })
+    }
+}
+
+impl FromRequest for Authenticated {
+    type Error = ActixError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::from_request_sync(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_lifecycle() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let a = Session::create(&db, "alice").unwrap();
+        let b = Session::create(&db, "alice").unwrap();
+        let c = Session::create(&db, "bob").unwrap();
+        assert_eq!(a.len(), TOKEN_LEN);
+        assert_ne!(a, b);
+
+        assert_eq!(Session::load(&db, &a).unwrap().unwrap().username, "alice");
+        assert!(Session::load(&db, "bogus").unwrap().is_none());
+
+        Session::revoke(&db, &a).unwrap();
+        assert!(Session::load(&db, &a).unwrap().is_none());
+        assert!(Session::load(&db, &b).unwrap().is_some());
+
+        Session::revoke_all(&db, "alice").unwrap();
+        assert!(Session::load(&db, &b).unwrap().is_none());
+        assert!(Session::load(&db, &c).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_reap() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let a = Session::create(&db, "alice").unwrap();
+        let b = Session::create(&db, "bob").unwrap();
+        Session::csrf_token(&db, &a).unwrap();
+        Session::csrf_token(&db, &b).unwrap();
+
+        // Left behind by a revoked session
+        store::csrf_tokens(&db)
+            .unwrap()
+            .insert("gone", &Text("csrf".to_owned()))
+            .unwrap();
+
+        assert_eq!(Session::reap(&db, SystemTime::now()).unwrap(), 0);
+        assert_eq!(store::csrf_tokens(&db).unwrap().tree().len(), 2);
+
+        // Sessions are forgotten once they expire, even if they are never used again
+        let later = SystemTime::now() + SESSION_TTL;
+        assert_eq!(Session::reap(&db, later).unwrap(), 2);
+        assert!(store::sessions(&db).unwrap().tree().is_empty());
+        assert!(store::csrf_tokens(&db).unwrap().tree().is_empty());
+    }
+
+    #[test]
+    fn test_csrf_token() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let a = Session::create(&db, "alice").unwrap();
+        let b = Session::create(&db, "alice").unwrap();
+
+        // Every session has its own token, which stays the same until the session ends
+        let csrf = Session::csrf_token(&db, &a).unwrap();
+        assert_eq!(csrf.len(), TOKEN_LEN);
+        assert_eq!(Session::csrf_token(&db, &a).unwrap(), csrf);
+        assert_ne!(Session::csrf_token(&db, &b).unwrap(), csrf);
+
+        Session::revoke(&db, &a).unwrap();
+        assert!(store::csrf_tokens(&db).unwrap().get(&a).unwrap().is_none());
+    }
+}
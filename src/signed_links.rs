@@ -0,0 +1,263 @@
+use super::config::Config;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+use std::{str::FromStr, time::SystemTime};
+
+/// The fewest bytes a link key may have, the length of the HMAC-SHA256 output.
+pub const MIN_KEY_LEN: usize = 32;
+
+/// How the content in a feed page is linked to.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Random links recorded in the database, which only work for the session they were issued
+    /// to and are used up by the first full delivery of the content
+    Stored,
+
+    /// Links carrying what they grant, to which session and until when, signed with link_keys
+    /// so that no record of them is kept. Neither rendering a feed page nor following a link
+    /// touches the database
+    Signed,
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stored" => Ok(Self::Stored),
+            "signed" => Ok(Self::Signed),
+            _ => Err(format!(
+                "unknown link mode {:?}, expected stored or signed",
+                s
+            )),
+        }
+    }
+}
+
+/// What a signed link grants: the file in content_folder, to the session with the given hash of
+/// its token, until the expiry time in seconds since the epoch.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Claims {
+    pub content: String,
+    pub session: [u8; 32],
+    pub expires: u64,
+}
+
+/// Why a signed link was refused.
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// The link isn't a signed link at all
+    Malformed,
+
+    /// None of the link keys signed the link, either because it was tampered with or because
+    /// the key that did has been rotated out
+    BadSignature,
+
+    /// The link outlived its lifetime, or the subscription it was issued under
+    Expired,
+
+    /// The link was issued to another session
+    OtherSession,
+}
+
+/// Decodes a base64 link key from the config.
+pub fn decode_key(key: &str) -> Result<Vec<u8>, String> {
+    let key = base64::decode(key).map_err(|e| format!("link key is not base64: {}", e))?;
+    if key.len() < MIN_KEY_LEN {
+        return Err(format!(
+            "link keys must be at least {} bytes, not {}",
+            MIN_KEY_LEN,
+            key.len()
+        ));
+    }
+
+    Ok(key)
+}
+
+/// Seconds since the epoch. Times before the epoch are the epoch.
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Hashes a session token, so that links name the session they were issued to without
+/// carrying its token.
+pub fn session_hash(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+fn mac(key: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+
+    mac
+}
+
+/// Issues a link token granting the session the file in content_folder until link_lifetime_secs
+/// after now, or until paid_until if the subscription runs out sooner, signed with the first of
+/// link_keys.
+pub fn sign(
+    cfg: &Config,
+    content: &str,
+    session: &str,
+    paid_until: Option<SystemTime>,
+    now: SystemTime,
+) -> Result<String, String> {
+    let key = decode_key(
+        cfg.link_keys
+            .first()
+            .ok_or_else(|| "signed links require link_keys".to_owned())?,
+    )?;
+    let mut expires = unix_secs(now).saturating_add(cfg.link_lifetime().as_secs());
+    if let Some(paid_until) = paid_until {
+        expires = expires.min(unix_secs(paid_until));
+    }
+    let payload = bincode::serialize(&Claims {
+        content: content.to_owned(),
+        session: session_hash(session),
+        expires,
+    })
+    .map_err(|e| e.to_string())?;
+    let tag = mac(&key, &payload).finalize().into_bytes();
+
+    Ok(format!(
+        "{}.{}",
+        base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Checks that the token was signed with any of link_keys for the session and hasn't expired,
+/// returning what it grants.
+pub fn verify(
+    cfg: &Config,
+    token: &str,
+    session: &str,
+    now: SystemTime,
+) -> Result<Claims, VerifyError> {
+    let decode = |part| {
+        base64::decode_config(part, base64::URL_SAFE_NO_PAD).map_err(|_| VerifyError::Malformed)
+    };
+    let (payload, tag) = token.split_once('.').ok_or(VerifyError::Malformed)?;
+    let (payload, tag) = (decode(payload)?, decode(tag)?);
+
+    // Keys that have been rotated out of first place still vouch for the links they signed
+    if !cfg
+        .link_keys
+        .iter()
+        .filter_map(|key| decode_key(key).ok())
+        .any(|key| mac(&key, &payload).verify(&tag).is_ok())
+    {
+        return Err(VerifyError::BadSignature);
+    }
+
+    let claims: Claims = bincode::deserialize(&payload).map_err(|_| VerifyError::Malformed)?;
+    if claims.session != session_hash(session) {
+        return Err(VerifyError::OtherSession);
+    }
+
+    if claims.expires <= unix_secs(now) {
+        return Err(VerifyError::Expired);
+    }
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let mut cfg = Config {
+            link_mode: LinkMode::Signed,
+            link_keys: vec![base64::encode([1; MIN_KEY_LEN])],
+            ..Config::default()
+        };
+        let now = SystemTime::now();
+        let token = sign(&cfg, "a.jpg", "session", None, now).unwrap();
+
+        let claims = verify(&cfg, &token, "session", now).unwrap();
+        assert_eq!(claims.content, "a.jpg");
+        assert_eq!(
+            verify(&cfg, &token, "session", now + cfg.link_lifetime()),
+            Err(VerifyError::Expired)
+        );
+        assert_eq!(
+            verify(&cfg, &token, "other", now),
+            Err(VerifyError::OtherSession)
+        );
+
+        // Claims can't be changed without the key
+        let forged = sign(&cfg, "b.jpg", "session", None, now).unwrap();
+        let tampered = format!(
+            "{}.{}",
+            forged.split('.').next().unwrap(),
+            token.split('.').nth(1).unwrap()
+        );
+        assert_eq!(
+            verify(&cfg, &tampered, "session", now),
+            Err(VerifyError::BadSignature)
+        );
+        assert_eq!(
+            verify(&cfg, "a.jpg", "session", now),
+            Err(VerifyError::Malformed)
+        );
+
+        // Links signed with a key stay valid while it is kept after a new one
+        cfg.link_keys.insert(0, base64::encode([2; MIN_KEY_LEN]));
+        assert!(verify(&cfg, &token, "session", now).is_ok());
+        let rotated = sign(&cfg, "a.jpg", "session", None, now).unwrap();
+        assert_ne!(rotated, token);
+
+        cfg.link_keys.pop();
+        assert_eq!(
+            verify(&cfg, &token, "session", now),
+            Err(VerifyError::BadSignature)
+        );
+        assert!(verify(&cfg, &rotated, "session", now).is_ok());
+    }
+
+    #[test]
+    fn test_links_end_with_subscription() {
+        let cfg = Config {
+            link_mode: LinkMode::Signed,
+            link_keys: vec![base64::encode([1; MIN_KEY_LEN])],
+            ..Config::default()
+        };
+        let now = SystemTime::now();
+        let paid_until = now + cfg.link_lifetime() / 2;
+
+        let token = sign(&cfg, "a.jpg", "session", Some(paid_until), now).unwrap();
+        assert!(verify(&cfg, &token, "session", now).is_ok());
+        assert_eq!(
+            verify(&cfg, &token, "session", paid_until),
+            Err(VerifyError::Expired)
+        );
+
+        // Links issued after the subscription lapsed never work
+        let token = sign(
+            &cfg,
+            "a.jpg",
+            "session",
+            Some(now - cfg.link_lifetime()),
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            verify(&cfg, &token, "session", now),
+            Err(VerifyError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_decode_key() {
+        assert!(decode_key(&base64::encode([0; MIN_KEY_LEN])).is_ok());
+        assert!(decode_key(&base64::encode([0; 16])).is_err());
+        assert!(decode_key("not base64!").is_err());
+    }
+}
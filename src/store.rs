@@ -0,0 +1,859 @@
+use super::{
+    auth::{PostHist, PostHistV1},
+    backend::Deposit,
+    ledger::Subscription,
+    lightning::Invoice,
+    payout::{PayoutRun, SchedulerState},
+    session::Session,
+    user::User,
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, TransactionResult, TransactionalTree,
+    },
+    Db, IVec, Transactional, Tree,
+};
+
+use std::{
+    convert::TryInto,
+    fmt::{self, Display},
+    marker::PhantomData,
+    ops::RangeBounds,
+    path::Path,
+};
+
+/// The sled tree holding every registered user, keyed by username.
+const USERS_TREE: &'static str = "users";
+
+/// The sled tree holding the caption of every post, keyed by the path of its content.
+const POSTS_TREE: &'static str = "posts";
+
+/// The sled tree holding the content behind every link handed out in a feed, keyed by the
+/// link's id.
+const LINKS_TREE: &'static str = "links";
+
+/// The sled tree holding every session, keyed by its token.
+const SESSIONS_TREE: &'static str = "sessions";
+
+/// The sled tree holding the CSRF token of every session, keyed by the session's token.
+const CSRF_TREE: &'static str = "csrf_tokens";
+
+/// The sled tree mapping every deposit address we have generated to the user it belongs to.
+const ADDRESSES_TREE: &'static str = "deposit_addresses";
+
+/// The sled tree holding every deposit address that has received funds.
+const USED_ADDRESSES_TREE: &'static str = "used_addresses";
+
+/// The sled tree indexing every link by when it was issued, keyed by the big-endian issue time
+/// in milliseconds followed by the link's id.
+const LINK_EXPIRY_TREE: &'static str = "link_expiry";
+
+/// The sled tree holding every user's subscription, keyed by username.
+const LEDGER_TREE: &'static str = "ledger";
+
+/// The sled tree holding the deposits each user is still waiting on, keyed by username.
+const PENDING_DEPOSITS_TREE: &'static str = "pending_deposits";
+
+/// The sled tree holding every user's unsettled invoices, keyed by username.
+const INVOICES_TREE: &'static str = "invoices";
+
+/// The sled tree holding the payout scheduler's state under a single key.
+const PAYOUTS_TREE: &'static str = "payouts";
+
+/// The sled tree recording every payout run, keyed by its big-endian start time in
+/// milliseconds.
+const PAYOUT_RUNS_TREE: &'static str = "payout_runs";
+
+/// The sled tree marking the process running a sweep, while one runs.
+const SWEEP_LOCK_TREE: &'static str = "sweep_lock";
+
+/// The sled tree recording the version each table's records have been migrated to, keyed by
+/// the table's tree.
+const SCHEMA_TREE: &'static str = "schema";
+
+/// The error a transaction aborts with.
+pub type TxError = ConflictableTransactionError<String>;
+
+/// Aborts a transaction with the error.
+pub fn abort(e: impl Display) -> TxError {
+    ConflictableTransactionError::Abort(e.to_string())
+}
+
+/// Gets the result of a transaction, with the error it aborted with or the storage error that
+/// stopped it.
+pub fn committed<T>(res: TransactionResult<T, String>) -> Result<T, String> {
+    res.map_err(|e| match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.to_string(),
+    })
+}
+
+/// A record kept in a table. Records are stored in an envelope carrying the version they were
+/// written at, so that their layout can change without breaking the records already stored.
+pub trait Record: Serialize + DeserializeOwned {
+    /// The version records are written at. Bumped whenever the layout changes, along with a case
+    /// in upgrade decoding the previous layout.
+    const VERSION: u32;
+
+    /// Decodes a record written at an older version.
+    fn upgrade(version: u32, _body: &[u8]) -> Result<Self, String> {
+        Err(format!("no upgrade from version {}", version))
+    }
+}
+
+/// Captions are kept as bare strings.
+impl Record for String {
+    const VERSION: u32 = 1;
+}
+
+/// Entries of an index carry nothing but their key.
+impl Record for () {
+    const VERSION: u32 = 1;
+}
+
+/// Process marks on the sweep lock.
+impl Record for u64 {
+    const VERSION: u32 = 1;
+}
+
+/// A string that was kept as its bare UTF-8 bytes before its tree was a table. Those bytes are
+/// read as version 1.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Text(pub String);
+
+impl Record for Text {
+    const VERSION: u32 = 2;
+
+    fn upgrade(version: u32, body: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => String::from_utf8(body.to_vec())
+                .map(Text)
+                .map_err(|e| e.to_string()),
+            _ => Err(format!("no upgrade from version {}", version)),
+        }
+    }
+}
+
+/// A record as it is stored, along with the version it was written at.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    body: Vec<u8>,
+}
+
+impl Envelope {
+    /// Decodes the record, upgrading it if it was written at an older version.
+    fn open<V: Record>(&self) -> Result<V, String> {
+        if self.version == V::VERSION {
+            bincode::deserialize(&self.body).map_err(|e| e.to_string())
+        } else if self.version < V::VERSION {
+            V::upgrade(self.version, &self.body)
+        } else {
+            Err(format!(
+                "record version {} is newer than this version of the store supports ({})",
+                self.version,
+                V::VERSION
+            ))
+        }
+    }
+}
+
+/// A sled tree of records of a single type, keyed by a string or by bytes that sort in the
+/// order the records should be walked in.
+pub struct Table<V> {
+    /// The name of the tree
+    name: &'static str,
+    tree: Tree,
+    record: PhantomData<fn() -> V>,
+}
+
+impl<V: Record> Table<V> {
+    /// Opens the named tree as a table of V.
+    fn open(db: &Db, name: &'static str) -> Result<Self, String> {
+        db.open_tree(name)
+            .map(|tree| Self {
+                name,
+                tree,
+                record: PhantomData,
+            })
+            .map_err(|e| e.to_string())
+    }
+
+    /// Gets the underlying tree, to run a transaction over it.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Gets the record stored under the key, if any.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<V>, String> {
+        self.tree
+            .get(key)
+            .map_err(|e| e.to_string())?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    /// Stores the record under the key, replacing any record already there.
+    pub fn insert(&self, key: impl AsRef<[u8]>, value: &V) -> Result<(), String> {
+        self.tree
+            .insert(key.as_ref(), encode(value)?)
+            .map_err(|e| e.to_string())
+            .map(|_| ())
+    }
+
+    /// Removes the record stored under the key, if any.
+    pub fn remove(&self, key: impl AsRef<[u8]>) -> Result<(), String> {
+        self.tree.remove(key).map_err(|e| e.to_string()).map(|_| ())
+    }
+
+    /// Replaces the record stored under the key with new, but only if it is still current.
+    /// Either may be None for no record. Returns the record that was there instead if it wasn't
+    /// current.
+    pub fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        current: Option<&V>,
+        new: Option<&V>,
+    ) -> Result<Result<(), Option<V>>, String> {
+        match self
+            .tree
+            .compare_and_swap(
+                key,
+                current.map(encode).transpose()?,
+                new.map(encode).transpose()?,
+            )
+            .map_err(|e| e.to_string())?
+        {
+            Ok(()) => Ok(Ok(())),
+            Err(e) => e.current.map(|bytes| decode(&bytes)).transpose().map(Err),
+        }
+    }
+
+    /// Gets the record with the greatest key, with its key, if there are any.
+    pub fn last(&self) -> Result<Option<(IVec, V)>, String> {
+        self.tree
+            .last()
+            .map_err(|e| e.to_string())?
+            .map(|(key, bytes)| Ok((key, decode(&bytes)?)))
+            .transpose()
+    }
+
+    /// Gets the records stored under keys in the range, with their keys, in order of key.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(IVec, V), String>> {
+        self.tree.range(range).map(|entry| {
+            let (key, bytes) = entry.map_err(|e| e.to_string())?;
+
+            Ok((key, decode(&bytes)?))
+        })
+    }
+
+    /// Gets every record in the table, in order of key.
+    pub fn values(&self) -> impl Iterator<Item = Result<V, String>> {
+        self.tree
+            .iter()
+            .values()
+            .map(|bytes| decode(&bytes.map_err(|e| e.to_string())?))
+    }
+
+    /// Gets every record in the table with its key, in order of key.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, V), String>> {
+        self.tree.iter().map(|entry| {
+            let (key, bytes) = entry.map_err(|e| e.to_string())?;
+
+            Ok((decode_key(&key)?, decode(&bytes)?))
+        })
+    }
+
+    /// Gives typed access to the table's tree within a transaction.
+    pub fn within<'a>(&self, tx: &'a TransactionalTree) -> TxTable<'a, V> {
+        TxTable {
+            tx,
+            record: PhantomData,
+        }
+    }
+}
+
+/// A table's tree within a transaction.
+pub struct TxTable<'a, V> {
+    tx: &'a TransactionalTree,
+    record: PhantomData<fn() -> V>,
+}
+
+impl<'a, V: Record> TxTable<'a, V> {
+    /// Gets the record stored under the key, if any.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<V>, TxError> {
+        self.tx
+            .get(key)?
+            .map(|bytes| decode(&bytes).map_err(abort))
+            .transpose()
+    }
+
+    /// Stores the record under the key, replacing any record already there.
+    pub fn insert(&self, key: impl AsRef<[u8]>, value: &V) -> Result<(), TxError> {
+        self.tx
+            .insert(key.as_ref(), encode(value).map_err(abort)?)
+            .map(|_| ())
+            .map_err(TxError::from)
+    }
+
+    /// Removes the record stored under the key, if any.
+    pub fn remove(&self, key: impl AsRef<[u8]>) -> Result<(), TxError> {
+        self.tx
+            .remove(key.as_ref())
+            .map(|_| ())
+            .map_err(TxError::from)
+    }
+}
+
+/// Encodes the record in an envelope at its current version.
+fn encode<V: Record>(value: &V) -> Result<Vec<u8>, String> {
+    seal(
+        V::VERSION,
+        bincode::serialize(value).map_err(|e| e.to_string())?,
+    )
+}
+
+/// Encodes a record's body in an envelope at the given version.
+fn seal(version: u32, body: Vec<u8>) -> Result<Vec<u8>, String> {
+    bincode::serialize(&Envelope { version, body }).map_err(|e| e.to_string())
+}
+
+/// Decodes a record from its envelope, upgrading it if it was written at an older version.
+fn decode<V: Record>(bytes: &[u8]) -> Result<V, String> {
+    bincode::deserialize::<Envelope>(bytes)
+        .map_err(|e| e.to_string())?
+        .open()
+}
+
+fn decode_key(key: &IVec) -> Result<String, String> {
+    String::from_utf8(key.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Every registered user.
+pub fn users(db: &Db) -> Result<Table<User>, String> {
+    Table::open(db, USERS_TREE)
+}
+
+/// The caption of every post.
+pub fn posts(db: &Db) -> Result<Table<String>, String> {
+    Table::open(db, POSTS_TREE)
+}
+
+/// The content behind every link handed out in a feed.
+pub fn links(db: &Db) -> Result<Table<PostHist>, String> {
+    Table::open(db, LINKS_TREE)
+}
+
+/// Every session.
+pub fn sessions(db: &Db) -> Result<Table<Session>, String> {
+    Table::open(db, SESSIONS_TREE)
+}
+
+/// The CSRF token of every session.
+pub fn csrf_tokens(db: &Db) -> Result<Table<Text>, String> {
+    Table::open(db, CSRF_TREE)
+}
+
+/// The owner of every deposit address we have generated.
+pub fn deposit_addresses(db: &Db) -> Result<Table<Text>, String> {
+    Table::open(db, ADDRESSES_TREE)
+}
+
+/// Every deposit address that has received funds.
+pub fn used_addresses(db: &Db) -> Result<Table<()>, String> {
+    Table::open(db, USED_ADDRESSES_TREE)
+}
+
+/// The index of every link by when it was issued.
+pub fn link_expiry(db: &Db) -> Result<Table<()>, String> {
+    Table::open(db, LINK_EXPIRY_TREE)
+}
+
+/// The payout scheduler's state.
+pub fn payouts(db: &Db) -> Result<Table<SchedulerState>, String> {
+    Table::open(db, PAYOUTS_TREE)
+}
+
+/// Every payout run.
+pub fn payout_runs(db: &Db) -> Result<Table<PayoutRun>, String> {
+    Table::open(db, PAYOUT_RUNS_TREE)
+}
+
+/// The mark of the process running a sweep, while one runs.
+pub fn sweep_lock(db: &Db) -> Result<Table<u64>, String> {
+    Table::open(db, SWEEP_LOCK_TREE)
+}
+
+/// Every user's subscription.
+pub fn ledger(db: &Db) -> Result<Table<Subscription>, String> {
+    Table::open(db, LEDGER_TREE)
+}
+
+/// The deposits each user is still waiting on.
+pub fn pending_deposits(db: &Db) -> Result<Table<Vec<Deposit>>, String> {
+    Table::open(db, PENDING_DEPOSITS_TREE)
+}
+
+/// Every user's unsettled invoices.
+pub fn invoices(db: &Db) -> Result<Table<Vec<Invoice>>, String> {
+    Table::open(db, INVOICES_TREE)
+}
+
+/// The changes migrating the database made, or would make on a dry run.
+pub struct MigrationReport {
+    dry_run: bool,
+    changes: Vec<String>,
+}
+
+impl MigrationReport {
+    /// Determines whether or not the database was already up to date.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "the database is up to date");
+        }
+
+        if self.dry_run {
+            write!(f, "migrating the database would make these changes:")?;
+        } else {
+            write!(f, "migrated the database:")?;
+        }
+        for change in &self.changes {
+            write!(f, "\n  {}", change)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Brings the database up to date with this version of the store: every table's records are
+/// upgraded to their current version, and records left in the default tree by older versions
+/// are moved into their own trees. Nothing is changed on a dry run.
+pub fn migrate(db: &Db, dry_run: bool) -> Result<MigrationReport, String> {
+    let schema = db.open_tree(SCHEMA_TREE).map_err(|e| e.to_string())?;
+    let changes = vec![
+        migrate_table(&users(db)?, &schema, dry_run)?,
+        migrate_table(&posts(db)?, &schema, dry_run)?,
+        migrate_table(&links(db)?, &schema, dry_run)?,
+        migrate_table(&sessions(db)?, &schema, dry_run)?,
+        migrate_table(&csrf_tokens(db)?, &schema, dry_run)?,
+        migrate_table(&deposit_addresses(db)?, &schema, dry_run)?,
+        migrate_table(&used_addresses(db)?, &schema, dry_run)?,
+        migrate_table(&link_expiry(db)?, &schema, dry_run)?,
+        migrate_table(&ledger(db)?, &schema, dry_run)?,
+        migrate_table(&pending_deposits(db)?, &schema, dry_run)?,
+        migrate_table(&invoices(db)?, &schema, dry_run)?,
+        migrate_table(&payouts(db)?, &schema, dry_run)?,
+        migrate_table(&payout_runs(db)?, &schema, dry_run)?,
+        migrate_table(&sweep_lock(db)?, &schema, dry_run)?,
+        move_legacy_records(db, dry_run)?,
+    ];
+
+    // Users registered before deposit addresses were indexed are indexed here, so their
+    // deposits can be found
+    if !dry_run {
+        User::index_all(db)?;
+    }
+
+    Ok(MigrationReport {
+        dry_run,
+        changes: changes.into_iter().flatten().collect(),
+    })
+}
+
+/// Opens the store's database, migrating it to this version of the store first.
+pub fn open(path: &Path) -> Result<Db, String> {
+    let db = sled::open(path).map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+
+    let report = migrate(&db, false)?;
+    if !report.is_empty() {
+        println!("{}", report);
+    }
+
+    Ok(db)
+}
+
+/// Upgrades every record in the table to its current version, all at once, and records that the
+/// table is up to date. Records from before records were versioned are bare version 1 bodies.
+/// Returns a description of the upgrade, unless there was nothing to upgrade.
+fn migrate_table<V: Record>(
+    table: &Table<V>,
+    schema: &Tree,
+    dry_run: bool,
+) -> Result<Option<String>, String> {
+    let version = match schema.get(table.name).map_err(|e| e.to_string())? {
+        Some(version) => u32::from_be_bytes(
+            version
+                .as_ref()
+                .try_into()
+                .map_err(|_| format!("invalid schema version for {}", table.name))?,
+        ),
+        None => 0,
+    };
+    if version > V::VERSION {
+        return Err(format!(
+            "{} was migrated by a newer version of the store (to version {}, but only {} is \
+             supported)",
+            table.name,
+            version,
+            V::VERSION
+        ));
+    } else if version == V::VERSION {
+        return Ok(None);
+    }
+
+    let mut upgraded = Vec::new();
+    for entry in table.tree.iter() {
+        let (key, bytes) = entry.map_err(|e| e.to_string())?;
+        let envelope = if version == 0 {
+            Envelope {
+                version: 1,
+                body: bytes.to_vec(),
+            }
+        } else {
+            bincode::deserialize(&bytes).map_err(|e| e.to_string())?
+        };
+
+        let record: V = envelope.open().map_err(|e| {
+            format!(
+                "could not upgrade {} record {}: {}",
+                table.name,
+                String::from_utf8_lossy(&key),
+                e
+            )
+        })?;
+        upgraded.push((key, encode(&record)?));
+    }
+
+    if !dry_run {
+        committed((&table.tree, schema).transaction(|(tx_table, tx_schema)| {
+            for (key, bytes) in &upgraded {
+                tx_table.insert(key, bytes.as_slice())?;
+            }
+            tx_schema.insert(table.name, &V::VERSION.to_be_bytes()[..])?;
+
+            Ok(())
+        }))?;
+    }
+
+    Ok(if upgraded.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "{}: {} records upgraded from version {} to {}",
+            table.name,
+            upgraded.len(),
+            version,
+            V::VERSION
+        ))
+    })
+}
+
+/// Decodes a record written with bincode's defaults, only if nothing is left over. Records of
+/// different types shared the default tree, so a record that decodes with bytes to spare is
+/// some other type's.
+fn decode_exact<V: DeserializeOwned>(bytes: &[u8]) -> Option<V> {
+    bincode::options()
+        .with_fixint_encoding()
+        .deserialize(bytes)
+        .ok()
+}
+
+/// Moves users and captions out of the default tree, where older versions kept them keyed by
+/// their bincoded username or content path, and drops the links and feed ids kept alongside
+/// them, since those expire within minutes anyway. Anything unrecognized is left alone.
+/// Returns a description of the records moved and dropped, if there were any.
+fn move_legacy_records(db: &Db, dry_run: bool) -> Result<Option<String>, String> {
+    let (users, posts) = (users(db)?, posts(db)?);
+    let (mut moved_users, mut moved_captions, mut dropped) = (0, 0, 0);
+
+    for entry in db.iter() {
+        let (key, value) = entry.map_err(|e| e.to_string())?;
+        let name: String = match decode_exact(&key) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let is_feed_id = name.len() == 9 && name.bytes().all(|b| b.is_ascii_alphanumeric());
+        let user = decode_exact::<User>(&value).filter(|u| u.username == name);
+        let caption = decode_exact::<String>(&value).filter(|_| !is_feed_id);
+        let expired = decode_exact::<PostHistV1>(&value).is_some()
+            || (is_feed_id && decode_exact::<u64>(&value).is_some());
+
+        if user.is_some() {
+            moved_users += 1;
+        } else if caption.is_some() {
+            moved_captions += 1;
+        } else if expired {
+            dropped += 1;
+        } else {
+            continue;
+        }
+
+        if dry_run {
+            continue;
+        }
+
+        // Both were kept at what is now version 1, and are upgraded as they are read if their
+        // layout has changed since
+        let sealed = seal(1, value.to_vec())?;
+        committed((&**db, &users.tree, &posts.tree).transaction(
+            |(tx_default, tx_users, tx_posts)| {
+                if user.is_some() {
+                    tx_users.insert(name.as_bytes(), sealed.as_slice())?;
+                } else if caption.is_some() {
+                    tx_posts.insert(name.as_bytes(), sealed.as_slice())?;
+                }
+                tx_default.remove(&key)?;
+
+                Ok(())
+            },
+        ))?;
+    }
+
+    Ok(if moved_users + moved_captions + dropped == 0 {
+        None
+    } else {
+        Some(format!(
+            "default tree: {} users and {} captions moved to their own trees, {} expired links \
+             dropped",
+            moved_users, moved_captions, dropped
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{collections::HashSet, time::SystemTime};
+
+    #[test]
+    fn test_table() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let posts = posts(&db).unwrap();
+        assert_eq!(posts.get("/content/a.jpg").unwrap(), None);
+
+        posts
+            .insert("/content/b.jpg", &"second".to_owned())
+            .unwrap();
+        posts.insert("/content/a.jpg", &"first".to_owned()).unwrap();
+        assert_eq!(
+            posts.get("/content/a.jpg").unwrap(),
+            Some("first".to_owned())
+        );
+        assert_eq!(
+            posts.iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![
+                ("/content/a.jpg".to_owned(), "first".to_owned()),
+                ("/content/b.jpg".to_owned(), "second".to_owned())
+            ]
+        );
+
+        posts.tree().remove("/content/a.jpg").unwrap();
+        assert_eq!(posts.get("/content/a.jpg").unwrap(), None);
+        assert_eq!(
+            posts.iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![("/content/b.jpg".to_owned(), "second".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_transaction_aborts() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let (users, posts) = (users(&db).unwrap(), posts(&db).unwrap());
+
+        let res: Result<(), String> =
+            committed((users.tree(), posts.tree()).transaction(|(_, tx_posts)| {
+                posts
+                    .within(tx_posts)
+                    .insert("/content/a.jpg", &"lol".to_owned())?;
+
+                Err(abort("no such user"))
+            }));
+        assert_eq!(res, Err("no such user".to_owned()));
+        assert_eq!(posts.get("/content/a.jpg").unwrap(), None);
+    }
+
+    #[test]
+    fn test_migrate_legacy_database() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let legacy = |key: &str, value: Vec<u8>| {
+            db.insert(bincode::serialize(key).unwrap(), value).unwrap();
+        };
+
+        let u = User {
+            username: "lol".to_owned(),
+            btc_addresses: vec!["bcrt1qa".to_owned(), "bcrt1qb".to_owned()]
+                .into_iter()
+                .collect::<HashSet<_>>(),
+            password_hash: "waejaweof".to_owned(),
+            salt: [0; 16],
+        };
+        legacy("lol", bincode::serialize(&u).unwrap());
+        legacy("/content/a.jpg", bincode::serialize("a caption").unwrap());
+        legacy("aB3dE6gH9", bincode::serialize(&0usize).unwrap());
+        legacy(
+            "aB3dE6gH9.jpg",
+            bincode::serialize(&PostHistV1 {
+                path: "/content/a.jpg".to_owned(),
+                created_at: SystemTime::now(),
+            })
+            .unwrap(),
+        );
+        db.insert(b"unknown", b"left alone").unwrap();
+
+        // Subscriptions already had their own tree, but weren't versioned
+        db.open_tree(LEDGER_TREE)
+            .unwrap()
+            .insert(
+                b"lol",
+                bincode::serialize(&Subscription::new("lol".to_owned())).unwrap(),
+            )
+            .unwrap();
+
+        // Neither were these, some of which were kept as bare strings
+        let tree = |name| db.open_tree(name).unwrap();
+        tree(CSRF_TREE).insert(b"token", &b"csrf"[..]).unwrap();
+        tree(ADDRESSES_TREE)
+            .insert(b"bcrt1qc", &b"other"[..])
+            .unwrap();
+        tree(USED_ADDRESSES_TREE)
+            .insert(b"bcrt1qa", &[] as &[u8])
+            .unwrap();
+        tree(PAYOUTS_TREE)
+            .insert(
+                b"state",
+                bincode::serialize(&SchedulerState::default()).unwrap(),
+            )
+            .unwrap();
+
+        // A dry run only reports what would change
+        let report = migrate(&db, true).unwrap();
+        assert_eq!(
+            report.to_string(),
+            "migrating the database would make these changes:
+  csrf_tokens: 1 records upgraded from version 0 to 2
+  deposit_addresses: 1 records upgraded from version 0 to 2
+  used_addresses: 1 records upgraded from version 0 to 1
+  ledger: 1 records upgraded from version 0 to 1
+  payouts: 1 records upgraded from version 0 to 1
+  default tree: 1 users and 1 captions moved to their own trees, 2 expired links dropped"
+        );
+        assert_eq!(db.len(), 5);
+        assert!(ledger(&db).unwrap().get("lol").is_err());
+
+        assert!(!migrate(&db, false).unwrap().is_empty());
+        assert_eq!(
+            users(&db)
+                .unwrap()
+                .get("lol")
+                .unwrap()
+                .unwrap()
+                .btc_addresses,
+            u.btc_addresses
+        );
+        assert_eq!(
+            posts(&db).unwrap().get("/content/a.jpg").unwrap(),
+            Some("a caption".to_owned())
+        );
+        assert!(ledger(&db).unwrap().get("lol").unwrap().is_some());
+        assert_eq!(
+            csrf_tokens(&db).unwrap().get("token").unwrap(),
+            Some(Text("csrf".to_owned()))
+        );
+        assert_eq!(
+            used_addresses(&db).unwrap().get("bcrt1qa").unwrap(),
+            Some(())
+        );
+        assert!(payouts(&db).unwrap().get("state").unwrap().is_some());
+        assert_eq!(posts(&db).unwrap().iter().count(), 1);
+        assert_eq!(db.len(), 1);
+
+        // The moved user's deposit addresses are indexed alongside those indexed already
+        assert_eq!(User::deposit_addresses(&db).unwrap().len(), 3);
+
+        // Nothing is left to do the next time the store starts
+        assert_eq!(
+            migrate(&db, false).unwrap().to_string(),
+            "the database is up to date"
+        );
+    }
+
+    /// A record whose second version added a field.
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Note {
+        text: String,
+        pinned: bool,
+    }
+
+    impl Record for Note {
+        const VERSION: u32 = 2;
+
+        fn upgrade(version: u32, body: &[u8]) -> Result<Self, String> {
+            match version {
+                1 => bincode::deserialize(body)
+                    .map(|text| Note {
+                        text,
+                        pinned: false,
+                    })
+                    .map_err(|e| e.to_string()),
+                _ => Err(format!("no upgrade from version {}", version)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_upgrade() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let schema = db.open_tree(SCHEMA_TREE).unwrap();
+        let notes = Table::<Note>::open(&db, "notes").unwrap();
+        let upgraded = Note {
+            text: "hi".to_owned(),
+            pinned: false,
+        };
+
+        // Written before records were versioned
+        notes
+            .tree()
+            .insert(b"a", bincode::serialize("hi").unwrap())
+            .unwrap();
+        assert!(notes.get("a").is_err());
+        assert_eq!(
+            migrate_table(&notes, &schema, true).unwrap().unwrap(),
+            "notes: 1 records upgraded from version 0 to 2"
+        );
+        assert!(notes.get("a").is_err());
+
+        migrate_table(&notes, &schema, false).unwrap();
+        assert_eq!(notes.get("a").unwrap(), Some(upgraded));
+        assert_eq!(migrate_table(&notes, &schema, false).unwrap(), None);
+
+        // Records written at an older version after migrating are upgraded as they are read
+        notes
+            .tree()
+            .insert(b"b", seal(1, bincode::serialize("hi").unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(notes.get("b").unwrap().unwrap().text, "hi");
+
+        // Records from a newer version of the store are never misread
+        notes
+            .tree()
+            .insert(b"c", seal(3, Vec::new()).unwrap())
+            .unwrap();
+        assert!(notes.get("c").unwrap_err().contains("newer"));
+        schema.insert("notes", &3u32.to_be_bytes()[..]).unwrap();
+        assert!(migrate_table(&notes, &schema, false).is_err());
+    }
+}
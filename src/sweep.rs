@@ -0,0 +1,369 @@
+use super::{amount::Amount, backend::Deposit, ingress::Utxo};
+
+use sha2::{Digest, Sha256};
+
+use std::convert::TryInto;
+
+/// The largest transaction weight bitcoind will relay (MAX_STANDARD_TX_WEIGHT).
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+
+/// Version, locktime, and the segwit marker and flag.
+const TX_OVERHEAD_WEIGHT: u64 = 4 * (4 + 4) + 2;
+
+/// The smallest output bitcoind will relay for any address type at the default dust relay fee.
+const DUST_THRESHOLD: Amount = Amount::from_sat(546);
+
+/// The kinds of scripts our deposits and destination may use, which determine their size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptKind {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+}
+
+impl ScriptKind {
+    /// Classifies a scriptPubKey from its hex encoding, as reported by listunspent.
+    pub fn from_script_hex(script: &str) -> Option<Self> {
+        match (script.len(), &script[..script.len().min(4)]) {
+            (44, "0014") => Some(Self::P2wpkh),
+            (68, "0020") => Some(Self::P2wsh),
+            (68, "5120") => Some(Self::P2tr),
+            (46, "a914") if script.ends_with("87") => Some(Self::P2shP2wpkh),
+            (50, "76a9") if script.ends_with("88ac") => Some(Self::P2pkh),
+            _ => None,
+        }
+    }
+
+    /// Classifies an address from its encoding, on any network.
+    pub fn from_address(addr: &str) -> Self {
+        let lower = addr.to_ascii_lowercase();
+        let data = lower
+            .strip_prefix("bcrt1")
+            .or_else(|| lower.strip_prefix("bc1"))
+            .or_else(|| lower.strip_prefix("tb1"));
+
+        match data {
+            Some(data) if data.starts_with('p') => Self::P2tr,
+            Some(data) if data.len() > 45 => Self::P2wsh,
+            Some(_) => Self::P2wpkh,
+            None if addr.starts_with('3') || addr.starts_with('2') => Self::P2shP2wpkh,
+            None => Self::P2pkh,
+        }
+    }
+
+    /// The weight of an input spending this script, assuming worst-case signature sizes.
+    pub fn input_weight(self) -> u64 {
+        match self {
+            Self::P2pkh => 4 * 148,
+            Self::P2shP2wpkh => 4 * 64 + 108,
+            Self::P2wpkh => 4 * 41 + 108,
+            Self::P2wsh => 4 * 41 + 256,
+            Self::P2tr => 4 * 41 + 66,
+        }
+    }
+
+    /// The weight of an output paying to this script.
+    pub fn output_weight(self) -> u64 {
+        4 * match self {
+            Self::P2pkh => 34,
+            Self::P2shP2wpkh => 32,
+            Self::P2wpkh => 31,
+            Self::P2wsh | Self::P2tr => 43,
+        }
+    }
+}
+
+/// The weight of a transaction with the given inputs and a single output.
+fn tx_weight(input_weight: u64, n_inputs: usize, output: ScriptKind) -> u64 {
+    TX_OVERHEAD_WEIGHT + 4 * (varint_len(n_inputs) + 1) + input_weight + output.output_weight()
+}
+
+fn varint_len(n: usize) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ => 5,
+    }
+}
+
+/// The fee, rounded up, for a transaction of the given weight at a rate of sat/vB.
+fn fee_for_weight(weight: u64, fee_rate: u64) -> Amount {
+    Amount::from_sat(weight.div_ceil(4) * fee_rate)
+}
+
+/// The weight of an input spending the UTXO. Unrecognized scripts are priced as legacy P2PKH,
+/// the largest common input.
+fn utxo_input_weight(utxo: &Utxo) -> u64 {
+    ScriptKind::from_script_hex(&utxo.script_pub_key)
+        .unwrap_or(ScriptKind::P2pkh)
+        .input_weight()
+}
+
+/// A single sweep transaction, ready to be built and signed.
+#[derive(Debug)]
+pub struct SweepTx {
+    pub inputs: Vec<Deposit>,
+    pub total: Amount,
+    pub fee: Amount,
+
+    /// The amount the destination receives
+    pub value: Amount,
+    pub vsize: u64,
+}
+
+/// Every transaction needed to sweep a set of UTXOs at some fee rate.
+#[derive(Debug)]
+pub struct SweepPlan {
+    pub destination: String,
+
+    /// The fee rate used, in sat/vB
+    pub fee_rate: u64,
+    pub txs: Vec<SweepTx>,
+
+    /// UTXOs left behind because spending them costs more than they are worth
+    pub dust: Vec<Deposit>,
+}
+
+impl SweepPlan {
+    /// Divides the UTXOs into as few transactions as fit under the standardness limit, leaving
+    /// behind any that would cost more in fees than they are worth.
+    pub fn new(destination: &str, utxos: &[Utxo], fee_rate: u64) -> Self {
+        let output = ScriptKind::from_address(destination);
+        let mut plan = Self {
+            destination: destination.to_owned(),
+            fee_rate,
+            txs: Vec::new(),
+            dust: Vec::new(),
+        };
+
+        // Worth-spending inputs, with their weights
+        let mut spendable = Vec::new();
+        for utxo in utxos {
+            let weight = utxo_input_weight(utxo);
+
+            if utxo.amount <= fee_for_weight(weight, fee_rate) {
+                plan.dust.push(Deposit::from(utxo));
+            } else {
+                spendable.push((Deposit::from(utxo), weight));
+            }
+        }
+
+        let mut batch: Vec<Deposit> = Vec::new();
+        let mut batch_weight = 0;
+        for (utxo, weight) in spendable {
+            // Leave room for the input count varint to grow
+            if !batch.is_empty()
+                && tx_weight(batch_weight + weight, batch.len() + 1, output)
+                    > MAX_STANDARD_TX_WEIGHT
+            {
+                plan.push_tx(std::mem::take(&mut batch), batch_weight, output);
+                batch_weight = 0;
+            }
+
+            batch_weight += weight;
+            batch.push(utxo);
+        }
+
+        if !batch.is_empty() {
+            plan.push_tx(batch, batch_weight, output);
+        }
+
+        plan
+    }
+
+    fn push_tx(&mut self, inputs: Vec<Deposit>, input_weight: u64, output: ScriptKind) {
+        let weight = tx_weight(input_weight, inputs.len(), output);
+        let total: Amount = inputs.iter().map(|deposit| deposit.amount).sum();
+        let fee = fee_for_weight(weight, self.fee_rate);
+
+        match total.checked_sub(fee) {
+            Some(value) if value >= DUST_THRESHOLD => self.txs.push(SweepTx {
+                inputs,
+                total,
+                fee,
+                value,
+                vsize: weight.div_ceil(4),
+            }),
+
+            // Too little to be worth a transaction yet
+            _ => self.dust.extend(inputs),
+        }
+    }
+
+    /// The total amount spent across every transaction.
+    pub fn total(&self) -> Amount {
+        self.txs.iter().map(|tx| tx.total).sum()
+    }
+
+    /// The total amount the destination receives across every transaction.
+    pub fn value(&self) -> Amount {
+        self.txs.iter().map(|tx| tx.value).sum()
+    }
+
+    /// The total fee paid across every transaction.
+    pub fn fee(&self) -> Amount {
+        self.txs.iter().map(|tx| tx.fee).sum()
+    }
+
+    /// Fingerprints the plan's destination, inputs and amounts, so that a sweep approved from a
+    /// preview is only executed if it would still do exactly what was previewed. The fingerprint
+    /// only depends on the plan, so a preview taken by one build of the store can be executed by
+    /// another.
+    pub fn confirmation(&self) -> String {
+        // Strings are prefixed with their length, so that no two plans hash the same bytes
+        let mut hasher = Sha256::new();
+        let hash_str = |hasher: &mut Sha256, s: &str| {
+            hasher.update((s.len() as u64).to_be_bytes());
+            hasher.update(s);
+        };
+        hash_str(&mut hasher, &self.destination);
+        hasher.update(self.fee_rate.to_be_bytes());
+
+        for tx in &self.txs {
+            hasher.update((tx.inputs.len() as u64).to_be_bytes());
+            for deposit in &tx.inputs {
+                hash_str(&mut hasher, &deposit.txid);
+                hasher.update(deposit.vout.to_be_bytes());
+            }
+
+            hasher.update(tx.value.as_sat().to_be_bytes());
+        }
+
+        let digest = hasher.finalize();
+        format!(
+            "{:016x}",
+            u64::from_be_bytes(
+                digest[..8]
+                    .try_into()
+                    .expect("SHA-256 digests are 32 bytes")
+            )
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p2wpkh_utxo(vout: u32, sat: u64) -> Utxo {
+        Utxo {
+            txid: "ab".repeat(32),
+            vout,
+            address: None,
+            script_pub_key: format!("0014{}", "00".repeat(20)),
+            amount: Amount::from_sat(sat),
+            confirmations: 6,
+        }
+    }
+
+    #[test]
+    fn test_script_kind() {
+        assert_eq!(
+            ScriptKind::from_address("bc1qy85uz8sf4w3erc695qfggzaexzt3tm7nkkmprk"),
+            ScriptKind::P2wpkh
+        );
+        assert_eq!(
+            ScriptKind::from_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2"),
+            ScriptKind::P2pkh
+        );
+        assert_eq!(
+            ScriptKind::from_address("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"),
+            ScriptKind::P2shP2wpkh
+        );
+        assert_eq!(
+            ScriptKind::from_script_hex(&format!("0014{}", "00".repeat(20))),
+            Some(ScriptKind::P2wpkh)
+        );
+        assert_eq!(ScriptKind::from_script_hex("6a"), None);
+    }
+
+    #[test]
+    fn test_plan_single_tx() {
+        let plan = SweepPlan::new(
+            "bc1qy85uz8sf4w3erc695qfggzaexzt3tm7nkkmprk",
+            &[p2wpkh_utxo(0, 20_000), p2wpkh_utxo(1, 30_000)],
+            10,
+        );
+
+        assert_eq!(plan.txs.len(), 1);
+        assert!(plan.dust.is_empty());
+
+        // 10.5 overhead, counts included, + 2 * 68 inputs + 31 output, rounded up
+        let tx = &plan.txs[0];
+        assert_eq!(tx.vsize, 178);
+        assert_eq!(tx.fee, Amount::from_sat(1_780));
+        assert_eq!(tx.value, Amount::from_sat(50_000 - 1_780));
+        assert_eq!(tx.total, tx.value.checked_add(tx.fee).unwrap());
+    }
+
+    #[test]
+    fn test_plan_skips_dust() {
+        let plan = SweepPlan::new(
+            "bc1qy85uz8sf4w3erc695qfggzaexzt3tm7nkkmprk",
+            &[p2wpkh_utxo(0, 20_000), p2wpkh_utxo(1, 600)],
+            10,
+        );
+
+        // The second input costs 680 sats to spend at this rate
+        assert_eq!(plan.txs.len(), 1);
+        assert_eq!(plan.txs[0].inputs.len(), 1);
+        assert_eq!(plan.dust.len(), 1);
+        assert_eq!(plan.dust[0].vout, 1);
+
+        // Nothing is worth sweeping at a high enough rate
+        let plan = SweepPlan::new(
+            "bc1qy85uz8sf4w3erc695qfggzaexzt3tm7nkkmprk",
+            &[p2wpkh_utxo(0, 20_000)],
+            500,
+        );
+        assert!(plan.txs.is_empty());
+        assert_eq!(plan.value(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_plan_batches() {
+        let utxos: Vec<Utxo> = (0..2_000).map(|i| p2wpkh_utxo(i, 10_000)).collect();
+        let plan = SweepPlan::new("bc1qy85uz8sf4w3erc695qfggzaexzt3tm7nkkmprk", &utxos, 1);
+
+        assert!(plan.txs.len() > 1);
+        assert_eq!(
+            plan.txs.iter().map(|tx| tx.inputs.len()).sum::<usize>(),
+            2_000
+        );
+        assert!(plan
+            .txs
+            .iter()
+            .all(|tx| tx.vsize * 4 <= MAX_STANDARD_TX_WEIGHT));
+        assert_eq!(
+            plan.value().checked_add(plan.fee()).unwrap(),
+            Amount::from_sat(2_000 * 10_000)
+        );
+    }
+
+    #[test]
+    fn test_confirmation() {
+        let dest = "bc1qy85uz8sf4w3erc695qfggzaexzt3tm7nkkmprk";
+        let utxos = [p2wpkh_utxo(0, 20_000), p2wpkh_utxo(1, 30_000)];
+        let plan = SweepPlan::new(dest, &utxos, 10);
+
+        assert_eq!(
+            plan.confirmation(),
+            SweepPlan::new(dest, &utxos, 10).confirmation()
+        );
+
+        // A new deposit or a new fee rate changes what would be broadcast
+        assert_ne!(
+            plan.confirmation(),
+            SweepPlan::new(dest, &[utxos[0].clone()], 10).confirmation()
+        );
+        assert_ne!(
+            plan.confirmation(),
+            SweepPlan::new(dest, &utxos, 11).confirmation()
+        );
+
+        // The same plan always has the same confirmation, whichever build of the store took it
+        assert_eq!(plan.confirmation(), "1c8fd9a4af548a44");
+    }
+}
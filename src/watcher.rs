@@ -0,0 +1,216 @@
+use super::{backend::PaymentBackend, config::Config, ledger};
+
+use actix_web::{
+    error, post,
+    web::{self, Path},
+    HttpRequest, HttpResponse, Result,
+};
+use sled::Db;
+use tokio::{sync::Notify, time};
+
+use std::sync::Arc;
+
+/// The header carrying notify_secret on posts to /notify.
+pub const NOTIFY_SECRET_HEADER: &'static str = "X-Notify-Secret";
+
+/// Wakes the deposit watcher early, when the node reports a new transaction or block.
+#[derive(Clone, Default)]
+pub struct WatchSignal(Arc<Notify>);
+
+impl WatchSignal {
+    /// Has the watcher sync as soon as it can. Signals received before it gets to them are
+    /// coalesced into a single sync.
+    pub fn wake(&self) {
+        self.0.notify_one();
+    }
+}
+
+/// Keeps every user's subscription and pending deposits up to date in the database, so that
+/// handlers never have to ask the payment backend for them.
+pub struct Watcher<'a> {
+    db: Db,
+    backend: &'a dyn PaymentBackend,
+    cfg: &'a Config,
+    signal: WatchSignal,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(
+        db: Db,
+        backend: &'a dyn PaymentBackend,
+        cfg: &'a Config,
+        signal: WatchSignal,
+    ) -> Self {
+        Self {
+            db,
+            backend,
+            cfg,
+            signal,
+        }
+    }
+
+    /// Syncs every user's deposits whenever the node notifies the store, and at least every
+    /// watch_interval_secs in case a notification was missed or never configured. Runs forever.
+    pub async fn run(self) {
+        loop {
+            if let Err(e) = ledger::sync_all(&self.db, self.backend, self.cfg).await {
+                eprintln!("deposit watcher error: {}", e);
+            }
+
+            // Timing out just means it's time to poll
+            let _ = time::timeout(self.cfg.watch_interval(), self.signal.0.notified()).await;
+        }
+    }
+}
+
+/// Wakes the watcher when bitcoind's -walletnotify or -blocknotify, or monero-wallet-rpc's
+/// --tx-notify, reports a transaction or block hash. The hash isn't trusted for anything: the
+/// watcher asks the payment backend for every deposit either way, which is why only the node,
+/// knowing notify_secret, may ask.
+#[post("/notify/{hash}")]
+pub async fn notify(
+    req: HttpRequest,
+    cfg: web::Data<Config>,
+    signal: web::Data<WatchSignal>,
+    hash: Path<String>,
+) -> Result<HttpResponse> {
+    let secret = cfg
+        .notify_secret
+        .as_ref()
+        .ok_or_else(|| error::ErrorNotFound("notifications are disabled"))?;
+    let given = req
+        .headers()
+        .get(NOTIFY_SECRET_HEADER)
+        .map(|given| given.as_bytes())
+        .unwrap_or_default();
+    if !secrets_match(given, secret.as_bytes()) {
+        return Err(error::ErrorForbidden("bad notify secret"));
+    }
+
+    let hash = hash.into_inner();
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(error::ErrorBadRequest(
+            "expected a transaction or block hash",
+        ));
+    }
+
+    signal.wake();
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Compares the secrets in time independent of where they differ, so that the secret can't be
+/// guessed a byte at a time.
+pub fn secrets_match(given: &[u8], secret: &[u8]) -> bool {
+    given.len() == secret.len()
+        && given
+            .iter()
+            .zip(secret)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{amount::Amount, ledger::Subscription, mock_rpc::MockBitcoind, user::User};
+
+    use actix_web::{http::StatusCode, test, App};
+
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_notify() {
+        let signal = WatchSignal::default();
+        let cfg = Config {
+            notify_secret: Some("s3cret".to_owned()),
+            ..Config::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(cfg))
+                .app_data(web::Data::new(signal.clone()))
+                .service(notify),
+        )
+        .await;
+        let hash = format!("/notify/{}", "ab".repeat(32));
+
+        // Only the node may ask for a sync
+        let req = test::TestRequest::post().uri(&hash).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let req = test::TestRequest::post()
+            .uri(&hash)
+            .insert_header((NOTIFY_SECRET_HEADER, "s3crex"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/notify/not-a-hash")
+            .insert_header((NOTIFY_SECRET_HEADER, "s3cret"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let req = test::TestRequest::post()
+            .uri(&hash)
+            .insert_header((NOTIFY_SECRET_HEADER, "s3cret"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::ACCEPTED
+        );
+
+        // The wakeup is kept until the watcher gets to it
+        assert!(time::timeout(Duration::from_secs(1), signal.0.notified())
+            .await
+            .is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_watcher_syncs_on_notify() {
+        let node = MockBitcoind::start();
+        let backend = node.backend();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cfg = Config {
+            // Only a notification can wake the watcher up in time
+            watch_interval_secs: 3600,
+            ..Config::default()
+        };
+        let signal = WatchSignal::default();
+
+        let mut u = User::new("lol".to_owned(), "hunter2".to_owned()).unwrap();
+        u.btc_addresses.insert("bcrt1qa".to_owned());
+        assert!(u.register(&db).unwrap());
+
+        let paid = async {
+            // Let the watcher's first sync go by
+            time::sleep(Duration::from_millis(100)).await;
+            node.wallet().deposit("bcrt1qa", cfg.monthly_btc, 1);
+            signal.wake();
+
+            for _ in 0..100 {
+                if Subscription::load(&db, "lol").unwrap().is_active() {
+                    return true;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+
+            false
+        };
+
+        tokio::select! {
+            _ = Watcher::new(db.clone(), &backend, &cfg, signal.clone()).run() => unreachable!(),
+            paid = paid => assert!(paid),
+        }
+        assert_eq!(Subscription::load(&db, "lol").unwrap().credit, Amount::ZERO);
+    }
+}